@@ -2,7 +2,8 @@ use crossterm::event::{KeyCode, KeyEvent};
 
 pub enum EditorAction {
     None,
-    Exit,
+    Exit,      // Refused while there are unsaved changes
+    ForceExit, // Quits anyway, losing them
     Save,
 }
 
@@ -31,9 +32,14 @@ pub(crate) enum NormalAction {
     DeleteChar,
     Change,
     Yank,
+    Undo,
+    Redo,
 
     LineStart,
     LineEnd,
+    MatchPair,
+    SetMark,
+    GotoMark,
 
     Up,
     Down,
@@ -43,7 +49,11 @@ pub(crate) enum NormalAction {
     SearchMode,
     InsertMode,
     VisualMode,
+    CommandMode,
+    View,
+    Window,
     Exit,
+    ForceExit,
 
     None,
 }
@@ -58,6 +68,7 @@ pub(crate) enum VisualAction {
 
     LineStart,
     LineEnd,
+    MatchPair,
 
     Up,
     Down,
@@ -70,6 +81,49 @@ pub(crate) enum VisualAction {
     None,
 }
 
+/// Second key of a view command, e.g. `zz` to center the cursor line
+#[derive(Clone, Copy)]
+pub(crate) enum ViewAction {
+    Center,
+    Top,
+    Bottom,
+
+    ScrollUp,
+    ScrollDown,
+    ScrollLeft,
+    ScrollRight,
+
+    Cancel,
+}
+
+/// Second key of a window or tab command, e.g. `ws` to split the window
+#[derive(Clone, Copy)]
+pub(crate) enum WindowAction {
+    Split,
+    VSplit,
+    Close,
+    Only,
+
+    Next,
+    Up,
+    Down,
+    Left,
+    Right,
+
+    Grow,
+    Shrink,
+    Widen,
+    Narrow,
+    Equalize,
+
+    NewTab,
+    CloseTab,
+    NextTab,
+    PrevTab,
+
+    Cancel,
+}
+
 pub(crate) fn get_key_name(ke: &KeyEvent) -> String {
     match ke.code {
         KeyCode::Backspace => String::from("backspace"),
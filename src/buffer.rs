@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::{
+    editor::Position,
+    fileio::FileStamp,
+    options::Options,
+    swap::Swap,
+    syntax::{Grammar, Highlighter, Span},
+};
+
+/// One edit, enough to undo or redo it
+struct Change {
+    start: Position,
+    removed: String,
+    inserted: String,
+    group: usize, // Changes with the same group are undone together
+    id: usize,    // Unique within the buffer, starting from 1
+}
+
+/// The text of an open file along with everything that belongs to it rather than to the
+/// view of it
+pub(crate) struct Buffer {
+    pub id: usize, // Shown by :ls and used by :b, never reused
+    pub filename: String,
+    lines: Vec<String>, // Never empty, an empty file is a single empty line
+    pub modified: bool,
+    pub changes: usize, // Counts every edit, undo and redo, to tell when the text has changed
+    pub swap: Swap,
+    pub disk: Option<FileStamp>, // The file as it was last read or written
+    pub disk_changed: bool,      // Something else has changed the file since then
+    pub local_options: Options,  // Buffer-scoped options set for this buffer
+    pub filetype_options: Vec<&'static str>, // Those set by the filetype and not since
+    pub highlighter: Option<Highlighter>,
+    pub cursor: Position, // Where the cursor was when the buffer was last shown
+    pub view: (usize, usize), // term_x and term_y when the buffer was last shown
+    marks: BTreeMap<char, Position>,
+    undo: Vec<Change>,
+    redo: Vec<Change>,
+    group: usize,
+    next_change: usize,
+    saved: usize, // Id of the last change undo would take back when the file was saved
+}
+
+impl Buffer {
+    pub fn new(id: usize, filename: &str, mut lines: Vec<String>) -> Self {
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+
+        Buffer {
+            id,
+            filename: filename.to_string(),
+            lines,
+            modified: false,
+            changes: 0,
+            swap: Swap::default(),
+            disk: None,
+            disk_changed: false,
+            local_options: Options::empty(),
+            filetype_options: Vec::new(),
+            highlighter: None,
+            cursor: Position::new(0, 0),
+            view: (0, 0),
+            marks: BTreeMap::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            group: 0,
+            next_change: 1,
+            saved: 0,
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Replace the text from `start` up to `end` with `text`, which may span several lines,
+    /// and record it for undo. Returns the position just after the inserted text
+    pub fn edit(&mut self, start: Position, end: Position, text: &str) -> Position {
+        let (start, end) = self.clamp_range(start, end);
+        if start == end && text.is_empty() {
+            return start;
+        }
+        let (removed, after) = self.apply(start, end, text);
+
+        self.redo.clear();
+        self.undo.push(Change {
+            start,
+            removed,
+            inserted: text.to_string(),
+            group: self.group,
+            id: self.next_change,
+        });
+        self.next_change += 1;
+
+        after
+    }
+
+    /// The text matches its file, having just been read or written
+    pub fn set_saved(&mut self) {
+        self.modified = false;
+        self.saved = self.state();
+    }
+
+    /// The buffer no longer matches its file in a way undo can't take back, like converting
+    /// its line endings
+    pub fn set_converted(&mut self) {
+        self.modified = true;
+        self.saved = usize::MAX;
+    }
+
+    // Identifies the text as it is now, for telling when undo and redo return to the saved text
+    fn state(&self) -> usize {
+        self.undo.last().map_or(0, |c| c.id)
+    }
+
+    /// Replace the whole text as a single change, so it can be undone
+    pub fn replace_all(&mut self, text: &str) {
+        let last = self.lines.len() - 1;
+        let end = Position::new(last, self.lines[last].len());
+        self.break_undo();
+        self.edit(Position::new(0, 0), end, text);
+        self.break_undo();
+    }
+
+    /// Save the text to a swap file at `path`, for recovery after a crash
+    pub fn write_swap(&mut self, path: PathBuf, fsync: bool) -> Result<()> {
+        self.swap
+            .write(path, &self.filename, &self.lines, self.changes, fsync)
+    }
+
+    /// Changes made after this are undone separately from the ones before
+    pub fn break_undo(&mut self) {
+        if self.undo.last().is_some_and(|c| c.group == self.group) {
+            self.group += 1;
+        }
+    }
+
+    /// Undo the last group of changes, returning where the first of them started
+    pub fn undo(&mut self) -> Option<Position> {
+        let group = self.undo.last()?.group;
+        let mut pos = None;
+
+        while self.undo.last().is_some_and(|c| c.group == group) {
+            let change = self.undo.pop().unwrap();
+            let end = end_of(change.start, &change.inserted);
+            self.apply(change.start, end, &change.removed);
+            pos = Some(change.start);
+            self.redo.push(change);
+        }
+
+        self.group += 1;
+        self.modified = self.state() != self.saved;
+        pos
+    }
+
+    /// Redo the last undone group of changes, returning where the last of them ended
+    pub fn redo(&mut self) -> Option<Position> {
+        let group = self.redo.last()?.group;
+        let mut pos = None;
+
+        while self.redo.last().is_some_and(|c| c.group == group) {
+            let change = self.redo.pop().unwrap();
+            let end = end_of(change.start, &change.removed);
+            pos = Some(self.apply(change.start, end, &change.inserted).1);
+            self.undo.push(change);
+        }
+
+        self.group += 1;
+        self.modified = self.state() != self.saved;
+        pos
+    }
+
+    pub fn set_mark(&mut self, name: char, pos: Position) {
+        self.marks.insert(name, pos);
+    }
+
+    pub fn mark(&self, name: char) -> Option<Position> {
+        self.marks.get(&name).copied()
+    }
+
+    pub fn marks(&self) -> impl Iterator<Item = (&char, &Position)> {
+        self.marks.iter()
+    }
+
+    /// Syntax spans for the lines in `range`, along with the grammar their scopes belong to
+    pub fn spans(&mut self, range: Range<usize>) -> Option<(Rc<Grammar>, Vec<&[Span]>)> {
+        let highlighter = self.highlighter.as_mut()?;
+        Some((highlighter.grammar(), highlighter.spans(&self.lines, range)))
+    }
+
+    /// The nearest position to `pos` that is inside the text and on a character boundary
+    pub fn clamp(&self, pos: Position) -> Position {
+        self.clamp_to_char(pos, false)
+    }
+
+    // Keep a position inside the text, moving it to the start of the character it is in, or
+    // with `up` to the end, so a range ending inside a character includes all of it
+    fn clamp_to_char(&self, pos: Position, up: bool) -> Position {
+        let lnum = pos.lnum().min(self.lines.len() - 1);
+        let line = &self.lines[lnum];
+
+        let mut index = pos.index().min(line.len());
+        while !line.is_char_boundary(index) {
+            if up {
+                index += 1;
+            } else {
+                index -= 1;
+            }
+        }
+
+        Position::new(lnum, index)
+    }
+
+    /// Keep a range inside the buffer and on character boundaries, with an end inside a
+    /// character taking the whole character. An end past the last line, as for the last line
+    /// of a linewise textobject, goes to the end of the text, and takes the newline before
+    /// the range with it when the range starts a line
+    pub fn clamp_range(&self, start: Position, end: Position) -> (Position, Position) {
+        let last = self.lines.len() - 1;
+        let mut end = end;
+        if end.lnum() > last {
+            end = Position::new(last, self.lines[last].len());
+            if start.index() == 0 && start.lnum() > 0 && start.lnum() <= last {
+                let prev = start.lnum() - 1;
+                return (Position::new(prev, self.lines[prev].len()), end);
+            }
+        }
+
+        let (start, end) = (self.clamp(start), self.clamp_to_char(end, true));
+        if end < start {
+            (start, start)
+        } else {
+            (start, end)
+        }
+    }
+
+    // Make an edit without recording it, returning the removed text and the position after
+    // the inserted text
+    fn apply(&mut self, start: Position, end: Position, text: &str) -> (String, Position) {
+        let removed = self.text(start, end);
+        let head = &self.lines[start.lnum()][..start.index()];
+        let tail = &self.lines[end.lnum()][end.index()..];
+
+        let joined = format!("{}{}{}", head, text, tail);
+        let new_lines = joined.split('\n').map(String::from).collect::<Vec<_>>();
+        let tail_len = tail.len();
+
+        let last = new_lines.len() - 1;
+        let after = Position::new(start.lnum() + last, new_lines[last].len() - tail_len);
+        let removed_lines = end.lnum() - start.lnum();
+
+        self.lines.splice(start.lnum()..end.lnum() + 1, new_lines);
+
+        // The edited line itself counts as removed and inserted again
+        if let Some(highlighter) = &mut self.highlighter {
+            highlighter.lines_removed(start.lnum(), removed_lines + 1);
+            highlighter.lines_inserted(start.lnum(), last + 1);
+        }
+
+        // Marks after the edit move with the text, marks inside it move to its start
+        for mark in self.marks.values_mut() {
+            if *mark >= end {
+                let index = if mark.lnum() == end.lnum() {
+                    mark.index() - end.index() + after.index()
+                } else {
+                    mark.index()
+                };
+                *mark = Position::new(mark.lnum() - end.lnum() + after.lnum(), index);
+            } else if *mark > start {
+                *mark = start;
+            }
+        }
+
+        self.modified = true;
+        self.changes += 1;
+        (removed, after)
+    }
+
+    /// The text between two positions, with lines joined by newlines
+    pub fn text(&self, start: Position, end: Position) -> String {
+        let (start, end) = self.clamp_range(start, end);
+        if start.lnum() == end.lnum() {
+            return self.lines[start.lnum()][start.index()..end.index()].to_string();
+        }
+
+        let mut text = self.lines[start.lnum()][start.index()..].to_string();
+        for line in &self.lines[start.lnum() + 1..end.lnum()] {
+            text.push('\n');
+            text.push_str(line);
+        }
+        text.push('\n');
+        text.push_str(&self.lines[end.lnum()][..end.index()]);
+        text
+    }
+}
+
+/// Position just after `text` when it is inserted at `start`
+fn end_of(start: Position, text: &str) -> Position {
+    match text.rsplit_once('\n') {
+        Some((before, last)) => {
+            Position::new(start.lnum() + before.matches('\n').count() + 1, last.len())
+        }
+        None => Position::new(start.lnum(), start.index() + text.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> Buffer {
+        Buffer::new(1, "", text.split('\n').map(String::from).collect())
+    }
+
+    #[test]
+    fn undo_back_to_saved() {
+        let mut buf = buffer("abc");
+        buf.set_saved();
+        buf.edit(Position::new(0, 3), Position::new(0, 3), "d");
+        assert!(buf.modified);
+
+        buf.undo();
+        assert!(!buf.modified);
+        buf.redo();
+        assert!(buf.modified);
+
+        // Saved with the change made, so undoing it is what modifies the text
+        buf.set_saved();
+        assert!(!buf.modified);
+        buf.undo();
+        assert!(buf.modified);
+        buf.redo();
+        assert!(!buf.modified);
+        assert_eq!(buf.lines(), ["abcd"]);
+    }
+
+    #[test]
+    fn edit_after_undo_is_not_saved() {
+        let mut buf = buffer("abc");
+        buf.edit(Position::new(0, 0), Position::new(0, 1), "");
+        buf.set_saved();
+        buf.undo();
+        buf.break_undo();
+        buf.edit(Position::new(0, 0), Position::new(0, 1), "");
+        assert_eq!(buf.lines(), ["bc"]);
+        assert!(buf.modified);
+    }
+
+    #[test]
+    fn converted_never_matches() {
+        let mut buf = buffer("abc");
+        buf.set_saved();
+        buf.set_converted();
+        assert!(buf.modified);
+
+        buf.edit(Position::new(0, 0), Position::new(0, 0), "x");
+        buf.undo();
+        assert!(buf.modified);
+        buf.redo();
+        assert!(buf.modified);
+        assert!(buf.undo().is_some());
+        assert!(buf.undo().is_none());
+        assert!(buf.modified);
+    }
+
+    #[test]
+    fn groups_undo_together() {
+        let mut buf = buffer("abc");
+        buf.edit(Position::new(0, 3), Position::new(0, 3), "d");
+        buf.edit(Position::new(0, 4), Position::new(0, 4), "\ne");
+        buf.break_undo();
+        buf.edit(Position::new(0, 0), Position::new(0, 1), "");
+        assert_eq!(buf.lines(), ["bcd", "e"]);
+
+        assert_eq!(buf.undo(), Some(Position::new(0, 0)));
+        assert_eq!(buf.lines(), ["abcd", "e"]);
+        assert_eq!(buf.undo(), Some(Position::new(0, 3)));
+        assert_eq!(buf.lines(), ["abc"]);
+        assert!(!buf.modified);
+
+        assert_eq!(buf.redo(), Some(Position::new(1, 1)));
+        assert_eq!(buf.lines(), ["abcd", "e"]);
+        assert_eq!(buf.redo(), Some(Position::new(0, 0)));
+        assert_eq!(buf.lines(), ["bcd", "e"]);
+        assert!(buf.redo().is_none());
+    }
+
+    #[test]
+    fn clamp_lines() {
+        let buf = buffer("ab\ncd");
+        let pos = Position::new;
+        // dd on the last line takes the newline before it
+        assert_eq!(
+            buf.clamp_range(pos(1, 0), pos(2, 0)),
+            (pos(0, 2), pos(1, 2))
+        );
+        assert_eq!(
+            buf.clamp_range(pos(0, 0), pos(1, 0)),
+            (pos(0, 0), pos(1, 0))
+        );
+        assert_eq!(
+            buf.clamp_range(pos(0, 1), pos(5, 0)),
+            (pos(0, 1), pos(1, 2))
+        );
+
+        // dd on the only line empties it
+        let buf = buffer("abc");
+        assert_eq!(
+            buf.clamp_range(pos(0, 0), pos(1, 0)),
+            (pos(0, 0), pos(0, 3))
+        );
+        let buf = buffer("");
+        assert_eq!(
+            buf.clamp_range(pos(0, 0), pos(1, 0)),
+            (pos(0, 0), pos(0, 0))
+        );
+    }
+
+    #[test]
+    fn clamp_inside_chars() {
+        // é is 2 bytes, 中 is 3
+        let buf = buffer("a\u{e9}\u{4e2d}b");
+        let pos = Position::new;
+        assert_eq!(buf.clamp(pos(0, 2)), pos(0, 1));
+        assert_eq!(buf.clamp(pos(0, 5)), pos(0, 3));
+        assert_eq!(buf.clamp(pos(3, 9)), pos(0, 7));
+
+        // A range inside a character takes all of it
+        assert_eq!(
+            buf.clamp_range(pos(0, 2), pos(0, 2)),
+            (pos(0, 1), pos(0, 3))
+        );
+        assert_eq!(
+            buf.clamp_range(pos(0, 4), pos(0, 5)),
+            (pos(0, 3), pos(0, 6))
+        );
+        assert_eq!(
+            buf.clamp_range(pos(0, 2), pos(0, 4)),
+            (pos(0, 1), pos(0, 6))
+        );
+        assert_eq!(buf.text(pos(0, 2), pos(0, 4)), "\u{e9}\u{4e2d}");
+    }
+
+    #[test]
+    fn clamp_backwards() {
+        let buf = buffer("abc\ndef");
+        let pos = Position::new;
+        assert_eq!(
+            buf.clamp_range(pos(1, 2), pos(0, 1)),
+            (pos(1, 2), pos(1, 2))
+        );
+        assert_eq!(
+            buf.clamp_range(pos(0, 2), pos(0, 1)),
+            (pos(0, 2), pos(0, 2))
+        );
+
+        let mut buf = buf;
+        buf.edit(pos(1, 2), pos(0, 1), "x");
+        assert_eq!(buf.lines(), ["abc", "dexf"]);
+    }
+}
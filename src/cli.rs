@@ -0,0 +1,199 @@
+use std::path::Path;
+
+pub(crate) const USAGE: &str = "\
+Usage: vir [options] [file ...]
+
+Options:
+  +N               Start at line N of the next file, or the first one if none follow
+  +                Start at the last line
+  +command         Run a command after opening the files, like -c
+  file:line[:col]  Open a file at a line and column, unless a file has that whole name
+  -                Edit text read from stdin, keys are still read from the terminal
+  --pipe           Write the first file to stdout when quitting, for use in a pipeline
+                   like `cmd | vir --pipe - | cmd`. Quitting with :q! writes nothing and
+                   exits with an error instead
+  -R               Read-only, files can only be saved with :w!
+  -c command       Run a command after opening the files, can be given more than once
+  --clean          Ignore the config, syntax files and themes in ~/.config/vir
+  --               Treat everything after it as a file
+  -h, --help       Show this help
+  -v, --version    Show the version
+";
+
+/// A file to open, with where to put the cursor
+pub(crate) struct FileArg {
+    pub name: String,                     // "-" for stdin
+    pub position: Option<(usize, usize)>, // Line and column, counted from 1
+}
+
+/// What to do, from the command line arguments
+pub(crate) struct Args {
+    pub files: Vec<FileArg>,
+    pub readonly: bool,
+    pub commands: Vec<String>,
+    pub clean: bool,
+    pub pipe: bool,
+}
+
+pub(crate) enum Command {
+    Edit(Args),
+    Help,
+    Version,
+}
+
+pub(crate) fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut parsed = Args {
+        files: Vec::new(),
+        readonly: false,
+        commands: Vec::new(),
+        clean: false,
+        pipe: false,
+    };
+    // A line from +N, waiting for the file it applies to
+    let mut line = None;
+    let mut only_files = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if only_files || arg == "-" || !arg.starts_with(['-', '+']) {
+            let mut file = file_arg(arg);
+            if let Some(line) = line.take() {
+                file.position = Some((line, 1));
+            }
+            parsed.files.push(file);
+            continue;
+        }
+
+        match arg.as_str() {
+            "--" => only_files = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-v" | "--version" => return Ok(Command::Version),
+            "-R" => parsed.readonly = true,
+            "--clean" => parsed.clean = true,
+            "--pipe" => parsed.pipe = true,
+            "-c" => match args.next() {
+                Some(command) => parsed.commands.push(command),
+                None => return Err("-c needs a command".to_string()),
+            },
+            "+" => line = Some(usize::MAX),
+            _ if arg.starts_with('+') => match arg[1..].parse::<usize>() {
+                Ok(n) => line = Some(n),
+                Err(_) => parsed.commands.push(arg[1..].to_string()),
+            },
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+
+    if let (Some(line), Some(file)) = (line, parsed.files.first_mut()) {
+        file.position = Some((line, 1));
+    }
+
+    Ok(Command::Edit(parsed))
+}
+
+// Split a position off the end of a file name, as in compiler and grep output, unless there is
+// a file with the whole name
+fn file_arg(name: String) -> FileArg {
+    if name == "-" || Path::new(&name).exists() {
+        return FileArg {
+            name,
+            position: None,
+        };
+    }
+
+    let trimmed = name.strip_suffix(':').unwrap_or(&name);
+    let position = split_number(trimmed).map(|(rest, last)| match split_number(rest) {
+        Some((file, line)) => (file, line, last),
+        None => (rest, last, 1),
+    });
+
+    match position {
+        Some((file, line, col)) if !file.is_empty() => FileArg {
+            name: file.to_string(),
+            position: Some((line, col)),
+        },
+        _ => FileArg {
+            name,
+            position: None,
+        },
+    }
+}
+
+// Split `name:N` into the name and N
+fn split_number(s: &str) -> Option<(&str, usize)> {
+    let (rest, n) = s.rsplit_once(':')?;
+    Some((rest, n.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(args: &[&str]) -> Args {
+        match parse(args.iter().map(|a| a.to_string())) {
+            Ok(Command::Edit(args)) => args,
+            Ok(_) => panic!("not an edit: {:?}", args),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn files(args: &Args) -> Vec<(&str, Option<(usize, usize)>)> {
+        args.files
+            .iter()
+            .map(|f| (f.name.as_str(), f.position))
+            .collect()
+    }
+
+    #[test]
+    fn line_before_or_after_files() {
+        let args = edit(&["a", "+5", "b"]);
+        assert_eq!(files(&args), [("a", None), ("b", Some((5, 1)))]);
+
+        // With no file after it, it applies to the first
+        let args = edit(&["a", "b", "+7"]);
+        assert_eq!(files(&args), [("a", Some((7, 1))), ("b", None)]);
+
+        let args = edit(&["+", "a"]);
+        assert_eq!(files(&args), [("a", Some((usize::MAX, 1)))]);
+    }
+
+    #[test]
+    fn position_in_name() {
+        let args = edit(&[
+            "no/such/file.rs:10:5",
+            "no/such/file.rs:3",
+            "no/such/file.rs:4:",
+        ]);
+        assert_eq!(
+            files(&args),
+            [
+                ("no/such/file.rs", Some((10, 5))),
+                ("no/such/file.rs", Some((3, 1))),
+                ("no/such/file.rs", Some((4, 1))),
+            ]
+        );
+
+        // Only numbers count, and there has to be a name before them
+        let args = edit(&["no/such/file:x", ":12"]);
+        assert_eq!(files(&args), [("no/such/file:x", None), (":12", None)]);
+    }
+
+    #[test]
+    fn options_and_commands() {
+        let args = edit(&["-R", "--clean", "--pipe", "-c", "set nu", "+set list", "-"]);
+        assert!(args.readonly && args.clean && args.pipe);
+        assert_eq!(args.commands, ["set nu", "set list"]);
+        assert_eq!(files(&args), [("-", None)]);
+
+        assert!(parse(["-c".to_string()]).is_err());
+        assert!(parse(["--nope".to_string()]).is_err());
+        assert!(matches!(parse(["-h".to_string()]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn files_after_double_dash() {
+        let args = edit(&["--", "-R", "+5", "--"]);
+        assert!(!args.readonly);
+        assert_eq!(files(&args), [("-R", None), ("+5", None), ("--", None)]);
+    }
+}
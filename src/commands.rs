@@ -0,0 +1,390 @@
+use std::io::Result;
+
+use crate::{
+    actions::EditorAction,
+    editor::EditorState,
+    options::{OptionKind, OptionSpec, OptionValue},
+    window::Split,
+};
+
+/// Run a line entered in command mode
+pub(crate) fn execute(line: &str, state: &mut EditorState) -> Result<EditorAction> {
+    let line = line.trim();
+    let (name, args) = match line.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (line, ""),
+    };
+    // A trailing ! forces commands that would otherwise refuse, like :bd! on a modified buffer
+    let (name, force) = match name.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+
+    match name {
+        "" => (),
+        "w" | "write" if args.is_empty() && !force => return Ok(EditorAction::Save),
+        "w" | "write" => write(state, args, false, force),
+        "sav" | "saveas" => write(state, args, true, force),
+        // With several windows or tabs :q closes the focused window, only the last one quits
+        "q" | "quit" if state.window_count() > 1 || state.tab_count() > 1 => close_window(state),
+        "q" | "quit" | "qa" | "qall" | "quita" | "quitall" if force => {
+            return Ok(EditorAction::ForceExit)
+        }
+        "q" | "quit" | "qa" | "qall" | "quita" | "quitall" => return Ok(EditorAction::Exit),
+        "wq" | "x" | "xit" => {
+            write(state, args, false, force);
+            return Ok(EditorAction::Exit);
+        }
+        "clo" | "close" => close_window(state),
+        "on" | "only" => state.only_window(),
+        "sp" | "split" => split(state, Split::Horizontal, args),
+        "vs" | "vsplit" => split(state, Split::Vertical, args),
+        "tabnew" | "tabe" | "tabedit" => {
+            let file = (!args.is_empty()).then_some(args);
+            if let Err(e) = state.new_tab(file) {
+                state.error(e);
+            }
+        }
+        "tabc" | "tabclose" => {
+            let index = tab_index(state, args).and_then(|i| state.close_tab(i));
+            if let Err(e) = index {
+                state.error(e);
+            }
+        }
+        "tabo" | "tabonly" => state.only_tab(),
+        // :tabnext N goes to tab N rather than N tabs along, as in vim
+        "tabn" | "tabnext" if args.is_empty() => state.cycle_tab(1),
+        "tabn" | "tabnext" => match tab_index(state, args) {
+            Ok(index) => state.switch_tab(index),
+            Err(e) => state.error(e),
+        },
+        "tabp" | "tabprevious" | "tabN" | "tabNext" => state.cycle_tab(-1),
+        "tabm" | "tabmove" if args.is_empty() => state.move_tab(usize::MAX),
+        "tabm" | "tabmove" => match args.parse::<usize>() {
+            Ok(n) => state.move_tab(n),
+            Err(_) => state.error(format!("Invalid tab position: {}", args)),
+        },
+        "res" | "resize" => resize(state, Split::Horizontal, args),
+        "vert" | "vertical" => match args.split_once(char::is_whitespace).unwrap_or((args, "")) {
+            ("res" | "resize", size) => resize(state, Split::Vertical, size.trim()),
+            ("sp" | "split", file) => split(state, Split::Vertical, file.trim()),
+            _ => state.error("Only :resize and :split can be made vertical".to_string()),
+        },
+        "se" | "set" => set(state, args, false),
+        "setl" | "setlocal" => set(state, args, true),
+        "strip" | "stripwhitespace" => match state.strip_trailing_whitespace() {
+            0 => state.info("No trailing whitespace".to_string()),
+            1 => state.info("Stripped 1 line".to_string()),
+            n => state.info(format!("Stripped {} lines", n)),
+        },
+        "u" | "undo" => {
+            if !state.undo() {
+                state.info("Already at oldest change".to_string());
+            }
+        }
+        "red" | "redo" => {
+            if !state.redo() {
+                state.info("Already at newest change".to_string());
+            }
+        }
+        "marks" => match state.list_marks() {
+            marks if marks.is_empty() => state.info("No marks set".to_string()),
+            marks => state.info(marks.join("  ")),
+        },
+        "rec" | "recover" => {
+            if let Err(e) = state.recover_swap() {
+                state.error(e);
+            }
+        }
+        "swapdelete" => {
+            if let Err(e) = state.delete_swap() {
+                state.error(e);
+            }
+        }
+        "ls" | "buffers" | "files" => state.info(state.list_buffers().join("  ")),
+        "b" | "buffer" => match state.find_buffer(args) {
+            Ok(index) => state.switch_buffer(index),
+            Err(e) => state.error(e),
+        },
+        "bn" | "bnext" => state.cycle_buffer(1),
+        "bp" | "bprevious" | "bN" | "bNext" => state.cycle_buffer(-1),
+        "e" | "edit" => edit(state, args, force),
+        "bd" | "bdelete" => {
+            let index = if args.is_empty() {
+                Ok(state.current_buffer())
+            } else {
+                state.find_buffer(args)
+            };
+
+            if let Err(e) = index.and_then(|index| state.close_buffer(index, force)) {
+                state.error(e);
+            }
+        }
+        _ => state.error(format!("Not an editor command: {}", name)),
+    }
+
+    Ok(EditorAction::None)
+}
+
+/// Index of tab number `arg`, counting from 1, or of the current tab without one
+fn tab_index(state: &EditorState, arg: &str) -> std::result::Result<usize, String> {
+    if arg.is_empty() {
+        return Ok(state.current_tab());
+    }
+
+    match arg.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= state.tab_count() => Ok(n - 1),
+        _ => Err(format!("Invalid tab number: {}", arg)),
+    }
+}
+
+/// Handles `:edit [++enc=name] [file]`. Without a file the current one is read again
+fn edit(state: &mut EditorState, args: &str, force: bool) {
+    let (encoding, file) = match args.strip_prefix("++enc=") {
+        Some(rest) => {
+            let (encoding, file) = rest.split_once(' ').unwrap_or((rest, ""));
+            (Some(encoding), file.trim())
+        }
+        None => (None, args),
+    };
+
+    let result = if file.is_empty() {
+        state.reload_buffer(encoding, force)
+    } else {
+        state.edit_file(file, encoding, force)
+    };
+    if let Err(e) = result {
+        state.error(e);
+    }
+}
+
+/// Handles `:w [++p] [file]` and `:saveas [++p] file`. `++p` creates missing directories
+fn write(state: &mut EditorState, args: &str, rename: bool, force: bool) {
+    let (mkdir, file) = match args.strip_prefix("++p") {
+        Some(file) => (true, file.trim()),
+        None => (false, args),
+    };
+
+    if rename && file.is_empty() {
+        state.error("No file name".to_string());
+        return;
+    }
+
+    let file = (!file.is_empty()).then_some(file);
+    state.write_buffer(file, mkdir, rename, force);
+}
+
+fn close_window(state: &mut EditorState) {
+    if let Err(e) = state.close_window(state.current_window()) {
+        state.error(e);
+    }
+}
+
+/// Handles `:split`/`:vsplit` with an optional file to open in the new window
+fn split(state: &mut EditorState, split: Split, file: &str) {
+    let file = (!file.is_empty()).then_some(file);
+    if let Err(e) = state.split_window(split, file) {
+        state.error(e);
+    }
+}
+
+/// Handles `:resize`, which takes a size like `20`, or `+5`/`-5` to grow or shrink the window.
+/// Without a size the window is made as large as possible
+fn resize(state: &mut EditorState, split: Split, size: &str) {
+    if size.is_empty() {
+        state.resize_window_to(split, usize::MAX / 2);
+        return;
+    }
+
+    let result = if size.starts_with(['+', '-']) {
+        size.parse::<isize>().map(|n| state.resize_window(split, n))
+    } else {
+        size.parse::<usize>()
+            .map(|n| state.resize_window_to(split, n))
+    };
+
+    if result.is_err() {
+        state.error(format!("Invalid size: {}", size));
+    }
+}
+
+/// Handles `:set`/`:setlocal`. Each argument is one of `name`, `noname`, `invname`, `name!`,
+/// `name?` or `name=value`, mirroring vim
+fn set(state: &mut EditorState, args: &str, local: bool) {
+    if args.is_empty() {
+        let changed = OptionSpec::all()
+            .iter()
+            .filter(|spec| state.option(spec.name) != Some(&spec.default_value()))
+            .map(|spec| describe(spec, state))
+            .collect::<Vec<_>>();
+
+        state.info(changed.join(" "));
+        return;
+    }
+
+    let mut output = Vec::new();
+
+    for arg in args.split_whitespace() {
+        match set_arg(state, arg, local) {
+            Ok(Some(msg)) => output.push(msg),
+            Ok(None) => (),
+            Err(e) => {
+                state.error(e);
+                return;
+            }
+        }
+    }
+
+    if !output.is_empty() {
+        state.info(output.join(" "));
+    }
+}
+
+/// Apply a single `:set` argument, returning any output it produces
+pub(crate) fn set_arg(
+    state: &mut EditorState,
+    arg: &str,
+    local: bool,
+) -> std::result::Result<Option<String>, String> {
+    let unknown = |name: &str| format!("Unknown option: {}", name);
+
+    if let Some((name, value)) = arg.split_once(['=', ':']) {
+        let spec = OptionSpec::lookup(name).ok_or_else(|| unknown(name))?;
+        let value = spec.parse(value)?;
+        state.set_option(spec, value, local)?;
+        return Ok(None);
+    }
+
+    if let Some(name) = arg.strip_suffix('?') {
+        let spec = OptionSpec::lookup(name).ok_or_else(|| unknown(name))?;
+        return Ok(Some(describe(spec, state)));
+    }
+
+    let (name, toggle) = match arg.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => match arg.strip_prefix("inv") {
+            Some(name) if OptionSpec::lookup(arg).is_none() => (name, true),
+            _ => (arg, false),
+        },
+    };
+
+    if let Some(spec) = OptionSpec::lookup(name) {
+        return match spec.kind {
+            OptionKind::Bool => {
+                let value = !toggle || !state.option_bool(spec.name);
+                state.set_option(spec, OptionValue::Bool(value), local)?;
+                Ok(None)
+            }
+            _ if toggle => Err(format!("Cannot toggle non-boolean option: {}", spec.name)),
+            // Naming a non-boolean option shows its value
+            _ => Ok(Some(describe(spec, state))),
+        };
+    }
+
+    match name.strip_prefix("no").and_then(OptionSpec::lookup) {
+        Some(spec) if matches!(spec.kind, OptionKind::Bool) => {
+            state.set_option(spec, OptionValue::Bool(false), local)?;
+            Ok(None)
+        }
+        Some(spec) => Err(format!("Not a boolean option: {}", spec.name)),
+        None => Err(unknown(name)),
+    }
+}
+
+fn describe(spec: &OptionSpec, state: &EditorState) -> String {
+    match state.option(spec.name) {
+        Some(OptionValue::Bool(true)) => spec.name.to_string(),
+        Some(OptionValue::Bool(false)) => format!("no{}", spec.name),
+        Some(value) => format!("{}={}", spec.name, value),
+        None => format!("{}=", spec.name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, term::Term};
+
+    fn editor() -> EditorState {
+        let config = Config::init(true).unwrap();
+        EditorState::new(Vec::new(), Term::headless(80, 24), config)
+    }
+
+    fn set(state: &mut EditorState, arg: &str) -> std::result::Result<Option<String>, String> {
+        set_arg(state, arg, false)
+    }
+
+    #[test]
+    fn values() {
+        let mut state = editor();
+        set(&mut state, "ts=2").unwrap();
+        assert_eq!(state.option_int("tabstop"), 2);
+        set(&mut state, "tabstop:3").unwrap();
+        assert_eq!(state.option_int("tabstop"), 3);
+        set(&mut state, "cms=-- %s").unwrap();
+        assert_eq!(state.option_str("commentstring"), "-- %s");
+        set(&mut state, "wrapmotion=display").unwrap();
+        assert_eq!(state.option_str("wrapmotion"), "display");
+        set(&mut state, "list=on").unwrap();
+        assert!(state.option_bool("list"));
+
+        assert!(set(&mut state, "ts=two").is_err());
+        assert!(set(&mut state, "wrapmotion=sideways").is_err());
+        assert!(set(&mut state, "list=maybe").is_err());
+        assert!(set(&mut state, "nosuch=1").is_err());
+        assert_eq!(state.option_int("tabstop"), 3);
+        assert_eq!(state.option_str("wrapmotion"), "display");
+    }
+
+    #[test]
+    fn booleans() {
+        let mut state = editor();
+        set(&mut state, "list").unwrap();
+        assert!(state.option_bool("list"));
+        set(&mut state, "nolist").unwrap();
+        assert!(!state.option_bool("list"));
+        set(&mut state, "invlist").unwrap();
+        assert!(state.option_bool("list"));
+        set(&mut state, "list!").unwrap();
+        assert!(!state.option_bool("list"));
+        set(&mut state, "nu!").unwrap();
+        assert!(state.option_bool("number"));
+
+        assert!(set(&mut state, "ts!").is_err());
+        assert!(set(&mut state, "invts").is_err());
+        assert!(set(&mut state, "nots").is_err());
+        assert!(set(&mut state, "nosuch").is_err());
+    }
+
+    #[test]
+    fn showing() {
+        let mut state = editor();
+        assert_eq!(set(&mut state, "list?"), Ok(Some("nolist".to_string())));
+        assert_eq!(set(&mut state, "et?"), Ok(Some("expandtab".to_string())));
+        assert_eq!(set(&mut state, "ts?"), Ok(Some("tabstop=4".to_string())));
+        // Naming a non-boolean option shows it rather than setting it
+        assert_eq!(set(&mut state, "ts"), Ok(Some("tabstop=4".to_string())));
+        assert!(set(&mut state, "nosuch?").is_err());
+    }
+
+    #[test]
+    fn scopes() {
+        let mut state = editor();
+        let other = state.add_buffer("other", vec![String::new()]);
+
+        // :setlocal only changes this buffer, :set changes the others too
+        set_arg(&mut state, "ts=2", true).unwrap();
+        set(&mut state, "et!").unwrap();
+        state.switch_buffer(other);
+        assert_eq!(state.option_int("tabstop"), 4);
+        assert!(!state.option_bool("expandtab"));
+
+        set(&mut state, "ts=8").unwrap();
+        state.switch_buffer(0);
+        assert_eq!(state.option_int("tabstop"), 2);
+
+        // Global options can't be set locally
+        assert!(set_arg(&mut state, "list", true).is_err());
+        assert!(!state.option_bool("list"));
+    }
+}
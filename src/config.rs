@@ -1,12 +1,16 @@
-use std::fs;
 use std::collections::HashMap;
+use std::{env, fs};
 
 use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use toml::{Table, Value};
 
 use crate::{
-    actions::{InsertAction, NormalAction, VisualAction},
+    actions::{InsertAction, NormalAction, ViewAction, VisualAction, WindowAction},
     editor::{Position, TextObject},
+    options::{OptionSpec, OptionValue, Options},
+    syntax::{self, Grammar},
 };
 
 pub struct Config {
@@ -14,43 +18,95 @@ pub struct Config {
     normal: HashMap<String, NormalAction>,
     visual: HashMap<String, VisualAction>,
     textobjects: HashMap<String, TextObject>,
-    options: HashMap<String, i64>,
+    view: HashMap<String, ViewAction>,
+    window: HashMap<String, WindowAction>,
+    options: Options,
+    filetypes: HashMap<String, Options>, // Options from [filetype.<name>] sections
+    extensions: HashMap<String, String>, // Extension -> filetype, from the same sections
+    grammars: Vec<Rc<Grammar>>,
+    dir: Option<PathBuf>, // Where the user's config is, None when it is ignored
+    errors: Vec<String>,  // Problems loading the config, shown once the editor is up
 }
 
 impl Config {
-    pub fn init() -> Result<Config> {
-        let tab = match Config::generate_table() {
-            Ok(t) => t,
-            Err(_) => HashMap::new(),
-        };
+    /// Load the config, or just the defaults if `clean` is set
+    pub fn init(clean: bool) -> Result<Config> {
+        let dir = if clean { None } else { Config::user_dir() };
+        let tab = dir
+            .as_deref()
+            .and_then(|d| Config::generate_table(d).ok())
+            .unwrap_or_default();
+        let (grammars, errors) =
+            syntax::load_grammars(dir.as_deref().map(|d| d.join("syntax")).as_deref());
 
         Ok(Config {
             insert: Config::init_insert(&tab).unwrap(),
             normal: Config::init_normal(&tab).unwrap(),
             visual: Config::init_visual(&tab).unwrap(),
             textobjects: Config::init_textobjects(&tab).unwrap(),
+            view: Config::init_view(&Config::keys(&tab, "view")).unwrap(),
+            window: Config::init_window(&Config::keys(&tab, "window")).unwrap(),
             options: Config::init_options(&tab).unwrap(),
+            filetypes: Config::init_filetypes(&tab).unwrap(),
+            extensions: Config::init_extensions(&tab).unwrap(),
+            grammars,
+            dir,
+            errors,
         })
     }
 
     pub fn insert(&self, key: &str) -> Option<InsertAction> {
-        self.insert.get(key).map(|b| *b)
+        self.insert.get(key).copied()
     }
 
     pub fn normal(&self, key: &str) -> Option<NormalAction> {
-        self.normal.get(key).map(|b| *b)
+        self.normal.get(key).copied()
     }
 
     pub fn visual(&self, key: &str) -> Option<VisualAction> {
-        self.visual.get(key).map(|b| *b)
+        self.visual.get(key).copied()
     }
 
     pub fn textobject(&self, key: &str) -> Option<TextObject> {
-        self.textobjects.get(key).map(|b| *b)
+        self.textobjects.get(key).copied()
+    }
+
+    pub fn view(&self, key: &str) -> Option<ViewAction> {
+        self.view.get(key).copied()
+    }
+
+    pub fn window(&self, key: &str) -> Option<WindowAction> {
+        self.window.get(key).copied()
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    pub fn filetype_options(&self, filetype: &str) -> Option<&Options> {
+        self.filetypes.get(filetype)
+    }
+
+    pub fn filetype_for_extension(&self, ext: &str) -> Option<&str> {
+        self.extensions.get(ext).map(|ft| ft.as_str())
+    }
+
+    /// The grammar used to highlight a filetype, if there is one
+    pub fn grammar_for(&self, filetype: &str) -> Option<Rc<Grammar>> {
+        self.grammars.iter().find(|g| g.handles(filetype)).cloned()
+    }
+
+    /// The user's config directory, unless it is being ignored
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
     }
 
-    pub fn option(&self, key: &str) -> i64 {
-        self.options[key]
+    pub fn take_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.errors)
     }
 
     fn init_insert(tab: &HashMap<String, Value>) -> Result<HashMap<String, InsertAction>> {
@@ -206,6 +262,15 @@ impl Config {
             NormalAction::LineEnd,
         );
 
+        map.insert(
+            if tab.contains_key("matchpair") {
+                tab.get("matchpair").unwrap().to_string()
+            } else {
+                "%".to_string()
+            },
+            NormalAction::MatchPair,
+        );
+
         map.insert(
             if tab.contains_key("searchmode") {
                 tab.get("searchmode").unwrap().to_string()
@@ -242,6 +307,78 @@ impl Config {
             NormalAction::Exit,
         );
 
+        map.insert(
+            if tab.contains_key("forceexit") {
+                tab.get("forceexit").unwrap().to_string()
+            } else {
+                "Q".to_string()
+            },
+            NormalAction::ForceExit,
+        );
+
+        map.insert(
+            if tab.contains_key("viewprefix") {
+                tab.get("viewprefix").unwrap().to_string()
+            } else {
+                "z".to_string()
+            },
+            NormalAction::View,
+        );
+
+        map.insert(
+            if tab.contains_key("windowprefix") {
+                tab.get("windowprefix").unwrap().to_string()
+            } else {
+                "w".to_string()
+            },
+            NormalAction::Window,
+        );
+
+        map.insert(
+            if tab.contains_key("undo") {
+                tab.get("undo").unwrap().to_string()
+            } else {
+                "l".to_string()
+            },
+            NormalAction::Undo,
+        );
+
+        map.insert(
+            if tab.contains_key("redo") {
+                tab.get("redo").unwrap().to_string()
+            } else {
+                "L".to_string()
+            },
+            NormalAction::Redo,
+        );
+
+        map.insert(
+            if tab.contains_key("setmark") {
+                tab.get("setmark").unwrap().to_string()
+            } else {
+                "M".to_string()
+            },
+            NormalAction::SetMark,
+        );
+
+        map.insert(
+            if tab.contains_key("gotomark") {
+                tab.get("gotomark").unwrap().to_string()
+            } else {
+                "`".to_string()
+            },
+            NormalAction::GotoMark,
+        );
+
+        map.insert(
+            if tab.contains_key("commandmode") {
+                tab.get("commandmode").unwrap().to_string()
+            } else {
+                ":".to_string()
+            },
+            NormalAction::CommandMode,
+        );
+
         map.insert(
             if tab.contains_key("up") {
                 tab.get("up").unwrap().to_string()
@@ -347,6 +484,15 @@ impl Config {
             VisualAction::LineEnd,
         );
 
+        map.insert(
+            if tab.contains_key("matchpair") {
+                tab.get("matchpair").unwrap().to_string()
+            } else {
+                "%".to_string()
+            },
+            VisualAction::MatchPair,
+        );
+
         map.insert(
             if tab.contains_key("up") {
                 tab.get("up").unwrap().to_string()
@@ -391,7 +537,7 @@ impl Config {
             },
             VisualAction::NormalMode,
         );
-        
+
         map.insert(
             if tab.contains_key("exit") {
                 tab.get("exit").unwrap().to_string()
@@ -443,6 +589,15 @@ impl Config {
             TextObject::LineEnd(Position::new(0, 0), 0),
         );
 
+        map.insert(
+            if tab.contains_key("matchpair") {
+                tab.get("matchpair").unwrap().to_string()
+            } else {
+                "%".to_string()
+            },
+            TextObject::Pair(Position::new(0, 0), Position::new(0, 0)),
+        );
+
         map.insert(
             if tab.contains_key("cancelop") {
                 tab.get("exit").unwrap().to_string()
@@ -455,23 +610,335 @@ impl Config {
         Ok(map)
     }
 
-    fn init_options(tab: &HashMap<String, Value>) -> Result<HashMap<String, i64>> {
+    fn init_view(tab: &HashMap<String, String>) -> Result<HashMap<String, ViewAction>> {
         let mut map = HashMap::new();
 
         map.insert(
-            "tabstop".to_string(),
-            if tab.contains_key("tabstop") && tab["tabstop"].is_integer() {
-                tab["tabstop"].as_integer().unwrap()
+            if tab.contains_key("center") {
+                tab.get("center").unwrap().to_string()
             } else {
-                4
+                "z".to_string()
             },
+            ViewAction::Center,
+        );
+
+        map.insert(
+            if tab.contains_key("top") {
+                tab.get("top").unwrap().to_string()
+            } else {
+                "t".to_string()
+            },
+            ViewAction::Top,
+        );
+
+        map.insert(
+            if tab.contains_key("bottom") {
+                tab.get("bottom").unwrap().to_string()
+            } else {
+                "b".to_string()
+            },
+            ViewAction::Bottom,
+        );
+
+        map.insert(
+            if tab.contains_key("scrollup") {
+                tab.get("scrollup").unwrap().to_string()
+            } else {
+                "e".to_string()
+            },
+            ViewAction::ScrollUp,
+        );
+
+        map.insert(
+            if tab.contains_key("scrolldown") {
+                tab.get("scrolldown").unwrap().to_string()
+            } else {
+                "n".to_string()
+            },
+            ViewAction::ScrollDown,
+        );
+
+        map.insert(
+            if tab.contains_key("scrollleft") {
+                tab.get("scrollleft").unwrap().to_string()
+            } else {
+                "m".to_string()
+            },
+            ViewAction::ScrollLeft,
+        );
+
+        map.insert(
+            if tab.contains_key("scrollright") {
+                tab.get("scrollright").unwrap().to_string()
+            } else {
+                "i".to_string()
+            },
+            ViewAction::ScrollRight,
+        );
+
+        map.insert(
+            if tab.contains_key("cancelview") {
+                tab.get("cancelview").unwrap().to_string()
+            } else {
+                "esc".to_string()
+            },
+            ViewAction::Cancel,
         );
 
         Ok(map)
     }
 
-    fn generate_table() -> Result<HashMap<String, Value>> {
-        let file = fs::read_to_string("~/.config/vir/config.toml")?
+    fn init_window(tab: &HashMap<String, String>) -> Result<HashMap<String, WindowAction>> {
+        let mut map = HashMap::new();
+
+        map.insert(
+            if tab.contains_key("split") {
+                tab.get("split").unwrap().to_string()
+            } else {
+                "s".to_string()
+            },
+            WindowAction::Split,
+        );
+
+        map.insert(
+            if tab.contains_key("vsplit") {
+                tab.get("vsplit").unwrap().to_string()
+            } else {
+                "v".to_string()
+            },
+            WindowAction::VSplit,
+        );
+
+        map.insert(
+            if tab.contains_key("closewindow") {
+                tab.get("closewindow").unwrap().to_string()
+            } else {
+                "q".to_string()
+            },
+            WindowAction::Close,
+        );
+
+        map.insert(
+            if tab.contains_key("onlywindow") {
+                tab.get("onlywindow").unwrap().to_string()
+            } else {
+                "o".to_string()
+            },
+            WindowAction::Only,
+        );
+
+        map.insert(
+            if tab.contains_key("nextwindow") {
+                tab.get("nextwindow").unwrap().to_string()
+            } else {
+                "w".to_string()
+            },
+            WindowAction::Next,
+        );
+
+        map.insert(
+            if tab.contains_key("windowup") {
+                tab.get("windowup").unwrap().to_string()
+            } else {
+                "e".to_string()
+            },
+            WindowAction::Up,
+        );
+
+        map.insert(
+            if tab.contains_key("windowdown") {
+                tab.get("windowdown").unwrap().to_string()
+            } else {
+                "n".to_string()
+            },
+            WindowAction::Down,
+        );
+
+        map.insert(
+            if tab.contains_key("windowleft") {
+                tab.get("windowleft").unwrap().to_string()
+            } else {
+                "m".to_string()
+            },
+            WindowAction::Left,
+        );
+
+        map.insert(
+            if tab.contains_key("windowright") {
+                tab.get("windowright").unwrap().to_string()
+            } else {
+                "i".to_string()
+            },
+            WindowAction::Right,
+        );
+
+        map.insert(
+            if tab.contains_key("grow") {
+                tab.get("grow").unwrap().to_string()
+            } else {
+                "+".to_string()
+            },
+            WindowAction::Grow,
+        );
+
+        map.insert(
+            if tab.contains_key("shrink") {
+                tab.get("shrink").unwrap().to_string()
+            } else {
+                "-".to_string()
+            },
+            WindowAction::Shrink,
+        );
+
+        map.insert(
+            if tab.contains_key("widen") {
+                tab.get("widen").unwrap().to_string()
+            } else {
+                ">".to_string()
+            },
+            WindowAction::Widen,
+        );
+
+        map.insert(
+            if tab.contains_key("narrow") {
+                tab.get("narrow").unwrap().to_string()
+            } else {
+                "<".to_string()
+            },
+            WindowAction::Narrow,
+        );
+
+        map.insert(
+            if tab.contains_key("equalize") {
+                tab.get("equalize").unwrap().to_string()
+            } else {
+                "=".to_string()
+            },
+            WindowAction::Equalize,
+        );
+
+        map.insert(
+            if tab.contains_key("tabnew") {
+                tab.get("tabnew").unwrap().to_string()
+            } else {
+                "t".to_string()
+            },
+            WindowAction::NewTab,
+        );
+
+        map.insert(
+            if tab.contains_key("tabclose") {
+                tab.get("tabclose").unwrap().to_string()
+            } else {
+                "c".to_string()
+            },
+            WindowAction::CloseTab,
+        );
+
+        map.insert(
+            if tab.contains_key("tabnext") {
+                tab.get("tabnext").unwrap().to_string()
+            } else {
+                "]".to_string()
+            },
+            WindowAction::NextTab,
+        );
+
+        map.insert(
+            if tab.contains_key("tabprev") {
+                tab.get("tabprev").unwrap().to_string()
+            } else {
+                "[".to_string()
+            },
+            WindowAction::PrevTab,
+        );
+
+        map.insert(
+            if tab.contains_key("cancelwindow") {
+                tab.get("cancelwindow").unwrap().to_string()
+            } else {
+                "esc".to_string()
+            },
+            WindowAction::Cancel,
+        );
+
+        Ok(map)
+    }
+
+    // The keys bound in a section like [view], which may also be given at the top level like
+    // the other modes' keys
+    fn keys(tab: &HashMap<String, Value>, section: &str) -> HashMap<String, String> {
+        let table = match tab.get(section) {
+            Some(Value::Table(t)) => Some(t),
+            _ => None,
+        };
+
+        tab.iter()
+            .chain(table.into_iter().flatten())
+            .filter_map(|(k, v)| Some((k.to_ascii_lowercase(), v.as_str()?.to_string())))
+            .collect()
+    }
+
+    fn init_options(tab: &HashMap<String, Value>) -> Result<Options> {
+        let mut options = Options::defaults();
+
+        if let Ok(wc) = env::var("WORDCHARS") {
+            let spec = OptionSpec::lookup("wordchars").unwrap();
+            options.set(spec, OptionValue::Str(wc));
+        }
+
+        // Options may be given at the top level (e.g. `tabstop = 2`) or in an [options] table
+        options.apply_table(tab);
+        if let Some(Value::Table(t)) = tab.get("options") {
+            options.apply_table(t);
+        }
+
+        Ok(options)
+    }
+
+    fn init_filetypes(tab: &HashMap<String, Value>) -> Result<HashMap<String, Options>> {
+        let mut map = HashMap::new();
+
+        if let Some(Value::Table(filetypes)) = tab.get("filetype") {
+            for (name, section) in filetypes {
+                if let Value::Table(section) = section {
+                    let mut options = Options::empty();
+                    options.apply_table(section);
+                    map.insert(name.to_string(), options);
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn init_extensions(tab: &HashMap<String, Value>) -> Result<HashMap<String, String>> {
+        let mut map = HashMap::new();
+
+        if let Some(Value::Table(filetypes)) = tab.get("filetype") {
+            for (name, section) in filetypes {
+                if let Some(Value::Array(exts)) = section.get("extensions") {
+                    for ext in exts.iter().filter_map(|e| e.as_str()) {
+                        map.insert(ext.to_string(), name.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// The directory holding config.toml, `$XDG_CONFIG_HOME/vir` or `~/.config/vir`
+    fn user_dir() -> Option<PathBuf> {
+        match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("vir")),
+            _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("vir")),
+        }
+    }
+
+    fn generate_table(dir: &Path) -> Result<HashMap<String, Value>> {
+        let path = dir.join("config.toml");
+        let file = fs::read_to_string(path)?
             .parse::<Table>()
             .expect("Unable to parse config.toml");
 
@@ -484,3 +951,38 @@ impl Config {
         Ok(map)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> HashMap<String, Value> {
+        text.parse::<Table>().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn section_keys() {
+        let tab = table("top = \"T\"\nbottom = \"x\"\n[view]\ncenter = \"C\"\nbottom = \"B\"");
+        let view = Config::init_view(&Config::keys(&tab, "view")).unwrap();
+        assert!(matches!(view.get("C"), Some(ViewAction::Center)));
+        assert!(matches!(view.get("T"), Some(ViewAction::Top)));
+        assert!(!view.contains_key("\"T\""));
+
+        // The section wins over the top level
+        assert!(matches!(view.get("B"), Some(ViewAction::Bottom)));
+        assert!(!view.contains_key("x"));
+    }
+
+    #[test]
+    fn window_keys() {
+        let tab = table("split = \"x\"\n[window]\nvsplit = \"V\"\nsplit = \"S\"");
+        let window = Config::init_window(&Config::keys(&tab, "window")).unwrap();
+        assert!(matches!(window.get("V"), Some(WindowAction::VSplit)));
+        assert!(matches!(window.get("S"), Some(WindowAction::Split)));
+        assert!(!window.contains_key("x"));
+
+        let tab = table("split = \"x\"");
+        let window = Config::init_window(&Config::keys(&tab, "window")).unwrap();
+        assert!(matches!(window.get("x"), Some(WindowAction::Split)));
+    }
+}
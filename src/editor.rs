@@ -1,18 +1,29 @@
 use std::{
-    env,
-    fs::File,
-    io::{stdout, BufWriter, Result, Write},
-    thread::sleep,
+    fs,
+    io::{Error, ErrorKind, Result},
+    ops::Range,
+    path::Path,
     time::Duration,
 };
 
-use crossterm::{cursor, queue, style::Stylize, terminal};
-
 use crate::{
-    actions::{InsertAction, NormalAction, VisualAction},
+    actions::{InsertAction, NormalAction, ViewAction, VisualAction, WindowAction},
+    buffer::Buffer,
+    commands,
     config::Config,
+    encoding,
+    fileio::{self, FileData, FileStamp, WriteOptions},
+    filetype,
+    message::{MessageLevel, MessageQueue},
     modes::Mode,
-    term::Term,
+    options::{OptionScope, OptionSpec, OptionValue, Options},
+    statusline::{self, StatusInfo},
+    swap::{self, SwapFile},
+    syntax::Highlighter,
+    term::{display_col, index_at_col, Gutter, ListChars, Screen, Style, Term, View},
+    theme::{ColorMode, Theme},
+    window::{Layout, Rect, Separator, Split, Tab, Window},
+    wrap::Wrap,
 };
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -51,6 +62,7 @@ pub(crate) enum TextObject {
     Line(usize),              // Line number
     LineEnd(Position, usize), // Pos, # chars
     Word(Position, usize),    // Pos, # chars
+    Pair(Position, Position), // From a bracket to just after its match
 
     Selection(Position), // Position of cursor end of selection
 
@@ -61,7 +73,10 @@ pub(crate) enum TextObject {
 impl TextObject {
     pub fn get_start(&self) -> Position {
         match self {
-            TextObject::Char(p) | TextObject::LineEnd(p, _) | TextObject::Word(p, _) => *p,
+            TextObject::Char(p)
+            | TextObject::LineEnd(p, _)
+            | TextObject::Word(p, _)
+            | TextObject::Pair(p, _) => *p,
 
             TextObject::Line(lnum) => Position::new(*lnum, 0),
             TextObject::CancelOp => panic!("CancelOp has no start position"),
@@ -74,8 +89,9 @@ impl TextObject {
         match self {
             TextObject::Char(p) => Position::new(p.lnum, p.index + 1),
             TextObject::Line(lnum) => Position::new(lnum + 1, 0),
+            TextObject::Pair(_, end) => *end,
             TextObject::Word(p, c) | TextObject::LineEnd(p, c) => {
-                Position::new(p.lnum, p.index + *c as usize)
+                Position::new(p.lnum, p.index + *c)
             }
             TextObject::CancelOp => panic!("CancelOp has no start position"),
             TextObject::None => panic!("None has no start position"),
@@ -109,13 +125,18 @@ impl TextObject {
     }
 
     pub fn is_none(&self) -> bool {
-        match &self {
-            TextObject::None => true,
-            _ => false,
-        }
+        matches!(self, TextObject::None)
     }
 }
 
+/// Where to put the cursor line when scrolling the view around it
+#[derive(Clone, Copy)]
+pub(crate) enum ViewPosition {
+    Top,
+    Center,
+    Bottom,
+}
+
 pub struct Cursor {
     pos: Position,
 }
@@ -127,7 +148,15 @@ impl Cursor {
 }
 
 pub(crate) struct EditorState {
-    data: Vec<String>,
+    buffers: Vec<Buffer>,
+    current: usize, // Index in `buffers` of the buffer being edited
+    next_id: usize,
+    windows: Vec<Window>,
+    window: usize, // Index in `windows` of the focused window, whose view is held below
+    layout: Layout,
+    next_window_id: usize,
+    tabs: Vec<Tab>, // The current tab's entry is empty, its windows are held above
+    tab: usize,     // Index in `tabs` of the current tab
     term_y: usize,
     term_x: usize,
     mode: Mode,
@@ -135,12 +164,34 @@ pub(crate) struct EditorState {
     anchor: Position, // Position of anchor in visual mode
     term: Term,
     config: Config,
+    cmdline: String,
+    messages: MessageQueue,
+    pending: String,      // Keys of a partially entered command
+    count: Option<usize>, // Count typed before a normal mode command
+    theme: Theme,
+    output: Option<usize>, // Id of the buffer written to stdout when quitting, in pipe mode
 }
 
 impl EditorState {
-    pub fn new(data: Vec<String>, term: Term, config: Config) -> Self {
-        EditorState {
-            data,
+    /// Open the given files as buffers, editing the first. Files without any data don't exist
+    /// yet and start out empty. Without any files there is a single unnamed buffer
+    pub fn new(files: Vec<(String, Option<FileData>)>, term: Term, config: Config) -> Self {
+        let mut state = EditorState {
+            buffers: Vec::new(),
+            current: 0,
+            next_id: 1,
+            windows: vec![Window {
+                id: 1,
+                buffer: 0,
+                cursor: Position::new(0, 0),
+                term_x: 0,
+                term_y: 0,
+            }],
+            window: 0,
+            layout: Layout::Window(1),
+            next_window_id: 2,
+            tabs: vec![Tab::empty()],
+            tab: 0,
             term_y: 0,
             term_x: 0,
             mode: Mode::Insert,
@@ -150,267 +201,2160 @@ impl EditorState {
             anchor: Position::new(0, 0),
             term,
             config,
+            cmdline: String::new(),
+            messages: MessageQueue::default(),
+            pending: String::new(),
+            count: None,
+            theme: Theme::default(),
+            output: None,
+        };
+
+        for e in state.config.take_errors() {
+            state.warn(e);
+        }
+
+        match state.load_theme(state.option_str("theme"), state.option_str("colors")) {
+            Ok(theme) => state.theme = theme,
+            Err(e) => state.warn(e),
+        }
+
+        if files.is_empty() {
+            state.add_buffer("", vec![String::new()]);
+        }
+        for (filename, data) in files {
+            state.open_buffer(&filename, data);
         }
+
+        state
     }
 
-    pub fn mode(&self) -> &Mode {
-        &self.mode
+    fn buf(&self) -> &Buffer {
+        &self.buffers[self.current]
     }
 
-    pub fn cursor(&self) -> &Cursor {
-        &self.cursor
+    fn buf_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current]
     }
 
-    pub fn redraw(&mut self) -> Result<()> {
-        self.wrangle_cursor();
-        let upper_limit = self.data.len().min(self.term_y + self.term.height());
-        let (cursor, anchor) = self.get_selection_xy();
-        self.term.redraw(
-            self.term_x,
-            cursor,
-            anchor,
-            &self.mode,
-            &self.data[self.term_y..upper_limit],
-        )
+    /// Add a buffer to the end of the list and detect its filetype, without switching to it.
+    /// Returns its index
+    pub fn add_buffer(&mut self, filename: &str, data: Vec<String>) -> usize {
+        self.push_buffer(Buffer::new(self.next_id, filename, data))
     }
 
-    pub fn save_file(&mut self, name: &str) -> Result<()> {
-        let f = File::create(name).expect("Unable to open file for writing");
-        let mut writer = BufWriter::new(f);
+    fn push_buffer(&mut self, buffer: Buffer) -> usize {
+        self.buffers.push(buffer);
+        self.next_id += 1;
 
-        for line in self.data.iter() {
-            writeln!(writer, "{}", line)?;
-        }
+        // Filetype detection works on the current buffer
+        let current = self.current;
+        self.current = self.buffers.len() - 1;
+        self.detect_filetype();
+        self.current = current;
+
+        self.buffers.len() - 1
+    }
 
-        let mut stdout = stdout();
+    /// Add a buffer for a file read from disk, or an empty one if the file doesn't exist
+    fn open_buffer(&mut self, filename: &str, file: Option<FileData>) -> usize {
+        let Some(file) = file else {
+            self.info(format!("\"{}\" [New]", filename));
+            let index = self.add_buffer(filename, vec![String::new()]);
+            self.find_swap(index);
+            return index;
+        };
+
+        // Set before detecting the filetype so a modeline can still override them
+        let mut options = Options::empty();
+        set_file_options(&mut options, &file);
+        let mut buffer = Buffer::new(self.next_id, filename, file.lines);
+        buffer.local_options = options;
+        buffer.disk = file.stamp;
 
-        queue!(
-            stdout,
-            cursor::MoveTo(0, self.term.height() as u16 / 2),
-            terminal::Clear(terminal::ClearType::All),
-        )?;
+        let index = self.push_buffer(buffer);
+        self.find_swap(index);
+        index
+    }
 
-        print!("{}", "Successfully saved file".cyan().on_dark_grey());
-        stdout.flush()?;
-        sleep(Duration::from_millis(2000));
+    /// Edit another buffer, remembering where the cursor and view were in this one
+    pub fn switch_buffer(&mut self, index: usize) {
+        if index == self.current || index >= self.buffers.len() {
+            return;
+        }
 
-        self.redraw()?;
+        let (cursor, view) = (self.cursor.pos, (self.term_x, self.term_y));
+        let buf = self.buf_mut();
+        buf.cursor = cursor;
+        buf.view = view;
 
-        Ok(())
+        self.current = index;
+        self.cursor.pos = self.buf().cursor;
+        (self.term_x, self.term_y) = self.buf().view;
     }
 
-    fn wrangle_cursor(&mut self) {
-        // First make sure it is within the bounds of the text buffer
-        if self.cursor.pos.lnum >= self.data.len() {
-            self.cursor.pos.lnum = self.data.len() - 1;
+    /// Index of the buffer with the given id, or whose file name is or contains `name`
+    pub fn find_buffer(&self, name: &str) -> std::result::Result<usize, String> {
+        if let Ok(id) = name.parse::<usize>() {
+            return self
+                .buffers
+                .iter()
+                .position(|b| b.id == id)
+                .ok_or_else(|| format!("No buffer {}", id));
         }
 
-        if self.cursor.pos.index >= self.data[self.cursor.pos.lnum].len() {
-            // Insert mode can go one character farther right
-            self.cursor.pos.index = self.data[self.cursor.pos.lnum].len()
-                - if self.mode == Mode::Insert || self.data[self.cursor.pos.lnum].len() == 0 {
-                    0
-                } else {
-                    1
-                };
+        if let Some(i) = self.buffers.iter().position(|b| b.filename == name) {
+            return Ok(i);
         }
 
-        // Now reposition the terminal window so that it contains the cursor
-        if self.cursor.pos.lnum < self.term_y {
-            self.term_y = self.cursor.pos.lnum;
-        } else if self.cursor.pos.lnum >= self.term_y + self.term.height() {
-            self.term_y = self.cursor.pos.lnum - self.term.height() + 1;
+        let matches = self
+            .buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.filename.contains(name))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        match matches[..] {
+            [i] => Ok(i),
+            [] => Err(format!("No matching buffer for {}", name)),
+            _ => Err(format!("More than one match for {}", name)),
         }
+    }
+
+    /// Switch to the buffer `offset` places along the list, wrapping around
+    pub fn cycle_buffer(&mut self, offset: isize) {
+        let len = self.buffers.len() as isize;
+        let index = (self.current as isize + offset).rem_euclid(len);
+        self.switch_buffer(index as usize);
+    }
 
-        if self.cursor.pos.index < self.term_x {
-            self.term_x = self.cursor.pos.index;
-        } else if self.cursor.pos.index >= self.term_x + self.term.width() {
-            self.term_x = self.cursor.pos.index - self.term.width()
-                + if self.mode == Mode::Insert { 1 } else { 0 };
+    /// Open a file in a new buffer, or switch to it if it is already open. With `encoding`,
+    /// the file is read in that encoding, reading an open one again as for `reload_buffer`
+    pub fn edit_file(
+        &mut self,
+        filename: &str,
+        encoding: Option<&str>,
+        force: bool,
+    ) -> std::result::Result<(), String> {
+        if let Some(index) = self.buffers.iter().position(|b| b.filename == filename) {
+            self.switch_buffer(index);
+            return match encoding {
+                Some(_) => self.reload_buffer(encoding, force),
+                None => Ok(()),
+            };
         }
+
+        let encodings = self.encodings(encoding)?;
+        let data = fileio::read_file(filename, &encodings)
+            .map_err(|e| format!("Unable to open \"{}\": {}", filename, e))?;
+        let index = self.open_buffer(filename, data);
+
+        self.switch_buffer(index);
+        Ok(())
     }
 
-    fn get_selection_xy(&self) -> ((usize, usize), (usize, usize)) {
-        let cursor = (
-            self.cursor.pos.index - self.term_x,
-            self.cursor.pos.lnum - self.term_y,
-        );
-        let anchor = (
-            self.anchor.index - self.term_x,
-            self.anchor.lnum - self.term_y,
-        );
+    /// Read the current buffer's file again, in `encoding` if given. Unsaved changes are only
+    /// thrown away with `force`
+    pub fn reload_buffer(
+        &mut self,
+        encoding: Option<&str>,
+        force: bool,
+    ) -> std::result::Result<(), String> {
+        let filename = self.buf().filename.clone();
+        if filename.is_empty() {
+            return Err("No file name".to_string());
+        }
+        if self.buf().modified && !force {
+            return Err("No write since last change (add ! to override)".to_string());
+        }
 
-        (cursor, anchor)
+        self.reload(self.current, encoding)?;
+
+        self.cursor.pos = self.buf().clamp(self.cursor.pos);
+        self.info(format!(
+            "\"{}\"{} {}L read",
+            filename,
+            self.file_tags(),
+            self.buf().lines().len()
+        ));
+        Ok(())
     }
 
-    pub fn resize(&mut self, width: usize, height: usize) {
-        self.term.resize(width, height)
+    // Replace a buffer's text with what is in its file now, keeping it as one change that can
+    // be undone
+    fn reload(&mut self, index: usize, encoding: Option<&str>) -> std::result::Result<(), String> {
+        let encodings = self.encodings(encoding)?;
+        let buf = &mut self.buffers[index];
+        let file = fileio::read_file(&buf.filename, &encodings)
+            .map_err(|e| format!("Unable to open \"{}\": {}", buf.filename, e))?
+            .ok_or_else(|| format!("\"{}\" no longer exists", buf.filename))?;
+
+        buf.replace_all(&file.lines.join("\n"));
+        set_file_options(&mut buf.local_options, &file);
+        buf.set_saved();
+        buf.disk = file.stamp;
+        buf.disk_changed = false;
+        buf.cursor = buf.clamp(buf.cursor);
+        Ok(())
     }
 
-    pub fn insert_mode(&mut self) {
-        self.mode = Mode::Insert;
+    /// Put the cursor in buffer `index` at a line and column counted from 1, as given on the
+    /// command line. Past the end goes to the last line or column
+    pub fn place_cursor(&mut self, index: usize, line: usize, col: usize) {
+        let buf = &mut self.buffers[index];
+        let lnum = line.saturating_sub(1).min(buf.lines().len() - 1);
+        let text = &buf.lines()[lnum];
+        let col = text
+            .char_indices()
+            .nth(col.saturating_sub(1))
+            .map_or(text.len(), |(i, _)| i);
+
+        buf.cursor = Position::new(lnum, col);
+        if index == self.current {
+            self.cursor.pos = buf.cursor;
+        }
     }
 
-    pub fn normal_mode(&mut self) {
-        self.mode = Mode::Normal;
+    /// Mark buffer `index` as having unsaved changes, as for text read from stdin
+    pub fn set_unsaved(&mut self, index: usize) {
+        self.buffers[index].set_converted();
     }
 
-    pub fn visual_mode(&mut self) {
-        self.mode = Mode::Visual;
-        self.anchor = self.cursor.pos;
+    /// Write buffer `index` to stdout when quitting, for pipe mode. Its text is saved by that
+    /// if it isn't from a file
+    pub fn set_output(&mut self, index: usize) {
+        self.output = Some(self.buffers[index].id);
     }
 
-    pub fn get_word_textobject(&self, pos: Position) -> TextObject {
-        let word_chars = match env::var("WORDCHARS") {
-            Ok(wc) => wc,
-            Err(_) => String::from("*?_-.[]~=&;!#$%^(){}<>"),
+    /// Clear the screen when quitting
+    pub fn clear_screen(&mut self) -> Result<()> {
+        self.term.clear()
+    }
+
+    /// Whether it is safe to quit, with no unsaved changes. Otherwise reports the first buffer
+    /// that has some
+    pub fn can_quit(&mut self) -> bool {
+        let output = |b: &Buffer| Some(b.id) == self.output && b.filename.is_empty();
+        let Some(buf) = self.buffers.iter().find(|b| b.modified && !output(b)) else {
+            return true;
         };
 
-        TextObject::Word(
-            pos,
-            match self.data[pos.lnum][pos.index..].split_once(|c| word_chars.contains(c)) {
-                Some((w, _)) => w.len(),
-                None => self.data[pos.lnum].len() - pos.index,
-            },
-        )
+        let name = match buf.filename.as_str() {
+            "" => format!("buffer {}", buf.id),
+            name => format!("\"{}\"", name),
+        };
+        self.error(format!(
+            "No write since last change for {} (:w saves it, :q! quits anyway)",
+            name
+        ));
+        false
     }
 
-    pub fn get_lineend_textobject(&self, pos: Position) -> TextObject {
-        TextObject::LineEnd(pos, self.data[pos.lnum].len() - pos.index)
+    /// Look for files changed on disk by something else since they were read or written. With
+    /// `autoread`, buffers without unsaved changes are read again, otherwise it is reported
+    pub fn check_files(&mut self) {
+        let autoread = self.option_bool("autoread");
+        for index in 0..self.buffers.len() {
+            let buf = &mut self.buffers[index];
+            let Some(stamp) = &mut buf.disk else {
+                continue;
+            };
+            if buf.disk_changed || !stamp.check(&buf.filename) {
+                continue;
+            }
+            buf.disk_changed = true;
+
+            let filename = buf.filename.clone();
+            if autoread && !buf.modified {
+                match self.reload(index, None) {
+                    Ok(()) => self.info(format!(
+                        "\"{}\" changed on disk and was read again",
+                        filename
+                    )),
+                    Err(e) => self.error(e),
+                }
+                if index == self.current {
+                    self.cursor.pos = self.buf().clamp(self.cursor.pos);
+                }
+                continue;
+            }
+
+            let reload = if buf.modified {
+                ":e! reloads it, losing your changes"
+            } else {
+                ":e reloads it"
+            };
+            self.warn(format!("\"{}\" has changed on disk, {}", filename, reload));
+        }
     }
 
-    pub fn insert_text(&mut self, pos: Position, text: &str) {
-        let (p1, p2) = self.data[pos.lnum].split_at(pos.index);
-        self.data[pos.lnum] = format!("{}{}{}", p1, text, p2);
-        self.cursor.pos.index += text.len()
+    // The encodings to try when reading a file, just `encoding` if one is given
+    fn encodings(&self, encoding: Option<&str>) -> std::result::Result<String, String> {
+        match encoding {
+            Some(name) if encoding::lookup(name).is_none() => {
+                Err(format!("Unknown encoding: {}", name))
+            }
+            Some(name) => Ok(name.to_string()),
+            None => Ok(self.option_str("fileencodings").to_string()),
+        }
     }
 
-    pub fn replace(&mut self, txt_obj: TextObject, text: &str) {
-        if txt_obj.is_none() {
-            return;
+    /// How the current buffer's file is stored, where that differs from the usual, as shown
+    /// when reading or writing it
+    fn file_tags(&self) -> String {
+        let mut tags = String::new();
+        for (option, usual) in [("fileformat", "unix"), ("fileencoding", "utf-8")] {
+            let value = self.option_str(option);
+            if value != usual {
+                tags.push_str(&format!(" [{}]", value));
+            }
+        }
+        if self.option_bool("bomb") {
+            tags.push_str(" [BOM]");
+        }
+        if !self.option_bool("endofline") {
+            tags.push_str(" [noeol]");
+        }
+        tags
+    }
+
+    /// Close a buffer, refusing if it has unsaved changes unless `force` is set
+    pub fn close_buffer(&mut self, index: usize, force: bool) -> std::result::Result<(), String> {
+        let buf = &self.buffers[index];
+        if buf.modified && !force {
+            return Err(format!(
+                "No write since last change for buffer {} (add ! to override)",
+                buf.id
+            ));
+        }
+        if self.buffers.len() == 1 {
+            return Err("Cannot close the last buffer".to_string());
         }
 
-        let (start, end) = if let TextObject::Selection(_) = txt_obj {
-            txt_obj.get_selection_bounds(self.anchor)
+        // Windows showing it show the next buffer instead, or the previous one when closing
+        // the last in the list
+        let next = if index + 1 < self.buffers.len() {
+            index + 1
         } else {
-            txt_obj.get_bounds()
+            index - 1
         };
+        if index == self.current {
+            self.switch_buffer(next);
+        }
 
-        self.normal_mode();
+        // The focused window comes first, followed by the other windows of all tabs
+        let others = self.tabs.iter_mut().flat_map(|t| t.windows.iter_mut());
+        for (i, window) in self.windows.iter_mut().chain(others).enumerate() {
+            if i == self.window {
+                continue;
+            }
+            if window.buffer == index {
+                let buf = &self.buffers[next];
+                window.buffer = next;
+                window.cursor = buf.cursor;
+                (window.term_x, window.term_y) = buf.view;
+            }
+            if window.buffer > index {
+                window.buffer -= 1;
+            }
+        }
 
-        self.data[start.lnum] = format!(
-            "{}{}{}",
-            &self.data[start.lnum][..start.index],
-            text,
-            &self.data[end.lnum][end.index..]
-        );
+        self.buffers.remove(index);
+        if self.current > index {
+            self.current -= 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn current_buffer(&self) -> usize {
+        self.current
+    }
+
+    /// One line per buffer for :ls, like `2 %+ "main.rs" line 14`
+    pub fn list_buffers(&self) -> Vec<String> {
+        self.buffers
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let lnum = if i == self.current {
+                    self.cursor.pos.lnum
+                } else {
+                    b.cursor.lnum
+                };
+                format!(
+                    "{}{}{} \"{}\" line {}",
+                    b.id,
+                    if i == self.current { " %" } else { "  " },
+                    if b.modified { "+" } else { " " },
+                    if b.filename.is_empty() {
+                        "[No Name]"
+                    } else {
+                        &b.filename
+                    },
+                    lnum + 1
+                )
+            })
+            .collect()
+    }
 
-        for lnum in start.lnum + 1..end.lnum + 1 {
-            self.data.remove(lnum);
+    /// The area windows are arranged in, everything but the tab line and status line
+    fn screen_area(&self) -> Rect {
+        let tabline = self.show_tabline() as usize;
+        Rect {
+            x: 0,
+            y: tabline,
+            width: self.term.width(),
+            height: self.term.height().saturating_sub(tabline),
         }
     }
 
-    pub fn insert_newline(&mut self) {
-        let line = String::from(&self.data[self.cursor.pos.lnum]);
-        let (p1, p2) = line.split_at(self.cursor.pos.index);
+    /// Whether the tab line is shown, from `showtabline`
+    fn show_tabline(&self) -> bool {
+        match self.option_int("showtabline") {
+            0 => false,
+            1 => self.tabs.len() > 1,
+            _ => true,
+        }
+    }
 
-        self.data[self.cursor.pos.lnum] = p1.to_string();
-        self.data.insert(self.cursor.pos.lnum + 1, p2.to_string());
+    /// A label for each tab like ` 2 main.rs+ `, with whether it is the current one
+    fn tab_labels(&self) -> Vec<(String, bool)> {
+        self.tabs
+            .iter()
+            .enumerate()
+            .map(|(i, tab)| {
+                let buffer = if i == self.tab {
+                    self.current
+                } else {
+                    tab.windows[tab.window].buffer
+                };
+                let buf = &self.buffers[buffer];
+                let name = match buf.filename.rsplit_once('/') {
+                    _ if buf.filename.is_empty() => "[No Name]",
+                    Some((_, name)) => name,
+                    None => &buf.filename,
+                };
+                let label = format!(
+                    " {} {}{} ",
+                    i + 1,
+                    name,
+                    if buf.modified { "+" } else { "" }
+                );
+                (label, i == self.tab)
+            })
+            .collect()
+    }
 
-        self.cursor.pos.lnum += 1;
-        self.cursor.pos.index = self.term_x;
+    /// Where each window is on screen, in order from top left, and the separators between them
+    fn arrange(&self) -> (Vec<(usize, Rect)>, Vec<Separator>) {
+        let mut rects = Vec::new();
+        let mut separators = Vec::new();
+        self.layout
+            .arrange(self.screen_area(), &mut rects, &mut separators);
+        (rects, separators)
     }
 
-    pub fn delete_newline(&mut self, lnum: usize) {
-        let end_index = self.data[lnum].len();
+    /// Where the focused window is on screen
+    fn window_rect(&self) -> Rect {
+        let id = self.windows[self.window].id;
+        self.arrange()
+            .0
+            .into_iter()
+            .find(|(w, _)| *w == id)
+            .map_or(self.screen_area(), |(_, rect)| rect)
+    }
 
-        let line = self.data.remove(lnum + 1);
-        self.data[lnum] = format!("{}{}", self.data[lnum], line);
+    fn view_height(&self) -> usize {
+        self.window_rect().height.max(1)
+    }
 
-        self.cursor.pos.index = end_index;
-        self.cursor.pos.lnum = lnum;
+    fn view_width(&self) -> usize {
+        self.window_rect().width.max(1)
     }
 
-    pub fn delete(&mut self, txt_obj: TextObject) {
-        if txt_obj.is_none() {
-            return;
+    /// Give focus to the window at `index`, saving the view of the one that had it
+    fn focus_window(&mut self, index: usize) {
+        let window = &mut self.windows[self.window];
+        window.buffer = self.current;
+        window.cursor = self.cursor.pos;
+        (window.term_x, window.term_y) = (self.term_x, self.term_y);
+
+        self.load_window(index);
+    }
+
+    // Take the view of the window at `index` without saving the current one
+    fn load_window(&mut self, index: usize) {
+        let window = &self.windows[index];
+        self.window = index;
+        self.current = window.buffer;
+        self.cursor.pos = window.cursor;
+        (self.term_x, self.term_y) = (window.term_x, window.term_y);
+    }
+
+    /// Split the focused window in two, both showing its buffer, and focus the new one, which
+    /// goes above or to the left. Opens `filename` in the new window if given
+    pub fn split_window(
+        &mut self,
+        split: Split,
+        filename: Option<&str>,
+    ) -> std::result::Result<(), String> {
+        let rect = self.window_rect();
+        let total = match split {
+            Split::Horizontal => rect.height,
+            Split::Vertical => rect.width,
+        };
+        if total < 3 {
+            return Err("Not enough room".to_string());
         }
 
-        let (start, end) = if let TextObject::Selection(_) = txt_obj {
-            if self.anchor < self.cursor.pos {
-                self.cursor.pos = self.anchor;
-            }
-            txt_obj.get_selection_bounds(self.anchor)
+        let id = self.next_window_id;
+        self.next_window_id += 1;
+        self.layout
+            .split(self.windows[self.window].id, id, split, (total - 1) / 2);
+
+        self.focus_window(self.window);
+        let window = &self.windows[self.window];
+        self.windows.push(Window { id, ..*window });
+        self.focus_window(self.windows.len() - 1);
+
+        match filename {
+            Some(filename) => self.edit_file(filename, None, false),
+            None => Ok(()),
+        }
+    }
+
+    /// Close the window at `index`, the buffer it shows stays open
+    pub fn close_window(&mut self, index: usize) -> std::result::Result<(), String> {
+        // Closing the last window of a tab closes the tab
+        if self.windows.len() == 1 && self.tabs.len() > 1 {
+            return self.close_tab(self.tab);
+        }
+        if self.windows.len() == 1 {
+            return Err("Cannot close last window".to_string());
+        }
+
+        self.focus_window(self.window);
+        let closed = self.windows.remove(index);
+        self.layout.remove(closed.id);
+
+        let focus = if index < self.window || (index == self.window && index > 0) {
+            self.window - 1
         } else {
-            txt_obj.get_bounds()
+            self.window.min(self.windows.len() - 1)
         };
+        self.load_window(focus);
+        Ok(())
+    }
 
-        self.normal_mode();
+    /// Close every window but the focused one
+    pub fn only_window(&mut self) {
+        self.focus_window(self.window);
+        let id = self.windows[self.window].id;
+        self.windows.retain(|w| w.id == id);
+        self.layout = Layout::Window(id);
+        self.window = 0;
+    }
+
+    pub fn current_window(&self) -> usize {
+        self.window
+    }
+
+    pub fn window_count(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Focus the window `offset` places along from this one in screen order, wrapping around
+    pub fn cycle_window(&mut self, offset: isize) {
+        let order = self.arrange().0;
+        let id = self.windows[self.window].id;
+        let pos = order.iter().position(|(w, _)| *w == id).unwrap_or(0) as isize;
+        let (next, _) = order[(pos + offset).rem_euclid(order.len() as isize) as usize];
+
+        let index = self.windows.iter().position(|w| w.id == next).unwrap();
+        self.focus_window(index);
+    }
+
+    /// Focus the window on the other side of one of the focused window's edges, across the
+    /// separator. `forward` is down for a horizontal split and right for a vertical one
+    pub fn focus_neighbour(&mut self, split: Split, forward: bool) {
+        let rect = self.window_rect();
+        let (x, y) = match (split, forward) {
+            (Split::Horizontal, false) => (Some(rect.x), rect.y.checked_sub(2)),
+            (Split::Horizontal, true) => (Some(rect.x), Some(rect.y + rect.height + 1)),
+            (Split::Vertical, false) => (rect.x.checked_sub(2), Some(rect.y)),
+            (Split::Vertical, true) => (Some(rect.x + rect.width + 1), Some(rect.y)),
+        };
+        let (Some(x), Some(y)) = (x, y) else {
+            return;
+        };
 
-        self.data[start.lnum] = format!(
-            "{}{}",
-            &self.data[start.lnum][..start.index],
-            &self.data[end.lnum][end.index..]
+        if let Some((id, _)) = self.arrange().0.into_iter().find(|(_, r)| r.contains(x, y)) {
+            let index = self.windows.iter().position(|w| w.id == id).unwrap();
+            self.focus_window(index);
+        }
+    }
+
+    /// Make the focused window `delta` rows or columns bigger, taking the space from its
+    /// neighbour in the split
+    pub fn resize_window(&mut self, split: Split, delta: isize) {
+        let area = self.screen_area();
+        let id = self.windows[self.window].id;
+        self.layout.resize(area, id, split, delta);
+    }
+
+    /// Make the focused window `size` rows or columns, as far as its neighbours allow
+    pub fn resize_window_to(&mut self, split: Split, size: usize) {
+        let rect = self.window_rect();
+        let current = match split {
+            Split::Horizontal => rect.height,
+            Split::Vertical => rect.width,
+        };
+        self.resize_window(split, size as isize - current as isize);
+    }
+
+    /// Give every window the same share of the screen
+    pub fn equalize_windows(&mut self) {
+        let area = self.screen_area();
+        self.layout.equalize(area);
+    }
+
+    // Exchange the windows of the current tab with those stored for the tab at `index`
+    fn swap_tab(&mut self, index: usize) {
+        let tab = &mut self.tabs[index];
+        std::mem::swap(&mut self.windows, &mut tab.windows);
+        std::mem::swap(&mut self.window, &mut tab.window);
+        std::mem::swap(&mut self.layout, &mut tab.layout);
+    }
+
+    /// Make the tab at `index` current
+    pub fn switch_tab(&mut self, index: usize) {
+        if index == self.tab || index >= self.tabs.len() {
+            return;
+        }
+
+        self.focus_window(self.window);
+        self.swap_tab(self.tab);
+        self.tab = index;
+        self.swap_tab(index);
+        self.load_window(self.window);
+    }
+
+    /// Open a tab after the current one with a single window, showing `filename` if given or
+    /// else the current buffer
+    pub fn new_tab(&mut self, filename: Option<&str>) -> std::result::Result<(), String> {
+        self.focus_window(self.window);
+        let window = Window {
+            id: self.next_window_id,
+            ..self.windows[self.window]
+        };
+        self.next_window_id += 1;
+
+        self.tabs.insert(
+            self.tab + 1,
+            Tab {
+                layout: Layout::Window(window.id),
+                windows: vec![window],
+                window: 0,
+            },
         );
+        self.switch_tab(self.tab + 1);
 
-        for lnum in (start.lnum + 1..end.lnum + 1).rev() {
-            self.data.remove(lnum);
+        match filename {
+            Some(filename) => self.edit_file(filename, None, false),
+            None => Ok(()),
         }
     }
 
-    pub fn insert_bind(&self, key: &str) -> Option<InsertAction> {
-        self.config.insert(key)
+    /// Close the tab at `index` and its windows, the buffers they show stay open
+    pub fn close_tab(&mut self, index: usize) -> std::result::Result<(), String> {
+        if self.tabs.len() == 1 {
+            return Err("Cannot close last tab page".to_string());
+        }
+
+        if index == self.tab {
+            // Go to the next tab, or the previous one when closing the last
+            let next = if index + 1 < self.tabs.len() {
+                index + 1
+            } else {
+                index - 1
+            };
+            self.switch_tab(next);
+        }
+
+        self.tabs.remove(index);
+        if self.tab > index {
+            self.tab -= 1;
+        }
+
+        Ok(())
     }
 
-    pub fn normal_bind(&self, key: &str) -> Option<NormalAction> {
-        self.config.normal(key)
+    /// Close every tab but the current one
+    pub fn only_tab(&mut self) {
+        self.tabs = vec![Tab::empty()];
+        self.tab = 0;
     }
 
-    pub fn visual_bind(&self, key: &str) -> Option<VisualAction> {
-        self.config.visual(key)
+    /// Go to the tab `offset` places along from this one, wrapping around
+    pub fn cycle_tab(&mut self, offset: isize) {
+        let len = self.tabs.len() as isize;
+        let index = (self.tab as isize + offset).rem_euclid(len);
+        self.switch_tab(index as usize);
     }
 
-    pub fn textobject_bind(&self, key: &str) -> Option<TextObject> {
-        self.config.textobject(key)
+    /// Move the current tab to `index` in the tab line
+    pub fn move_tab(&mut self, index: usize) {
+        let tab = self.tabs.remove(self.tab);
+        self.tab = index.min(self.tabs.len());
+        self.tabs.insert(self.tab, tab);
     }
 
-    pub fn backspace(&mut self, pos: Position) {
-        self.data[pos.lnum].remove(pos.index - 1);
-        self.cursor_left()
+    pub fn current_tab(&self) -> usize {
+        self.tab
     }
 
-    pub fn indent(&mut self) {
-        // Want to make this configurable
-        let spaces = &(0..self.config.option("tabstop"))
-            .map(|_| ' ')
-            .collect::<String>();
-        let (p1, p2) = self.data[self.cursor.pos.lnum].split_at(self.cursor.pos.index);
-        self.data[self.cursor.pos.lnum] = format!("{}{}{}", p1, spaces, p2);
+    pub fn tab_count(&self) -> usize {
+        self.tabs.len()
+    }
 
-        self.cursor.pos.index += self.config.option("tabstop") as usize;
+    pub fn mode(&self) -> &Mode {
+        &self.mode
     }
 
-    pub fn cursor_right(&mut self) {
-        self.cursor.pos.index += 1;
+    pub fn cursor(&self) -> &Cursor {
+        &self.cursor
     }
 
-    pub fn cursor_left(&mut self) {
-        self.cursor.pos.index = self.cursor.pos.index.saturating_sub(1);
+    pub fn redraw(&mut self) -> Result<()> {
+        let (rects, separators) = self.arrange();
+        let focused = self.window;
+
+        // Each window is drawn as if it had focus, then the text is borrowed once they all are
+        let mut views = Vec::new();
+        let mut cursor = (0, 0);
+        for (id, rect) in rects {
+            let index = self.windows.iter().position(|w| w.id == id).unwrap();
+            self.focus_window(index);
+
+            let (view, range, window_cursor) = self.window_view(rect, index == focused);
+            if index == focused {
+                cursor = window_cursor;
+            }
+            views.push((view, self.current, range));
+        }
+        self.focus_window(focused);
+
+        let (status, status_highlight) = if self.mode == Mode::Command {
+            cursor = (self.cmdline.len() + 1, self.term.height());
+            (format!(":{}", self.cmdline), None)
+        } else {
+            let message = self
+                .messages
+                .current()
+                .map(|m| (m.text.clone(), m.level.element()));
+
+            match message {
+                Some((text, element)) => {
+                    let (status, range) = self.status_line(&text);
+                    (status, range.map(|r| (r, self.theme.ui(element))))
+                }
+                None => (self.status_line("").0, None),
+            }
+        };
+
+        let views = views
+            .into_iter()
+            .map(|(view, buffer, range)| View {
+                lines: &self.buffers[buffer].lines()[range],
+                ..view
+            })
+            .collect();
+
+        let tabline = if self.show_tabline() {
+            self.tab_labels()
+        } else {
+            Vec::new()
+        };
+
+        self.term.redraw(&Screen {
+            tabline,
+            views,
+            separators,
+            cursor,
+            mode: &self.mode,
+            status: &status,
+            status_highlight,
+            theme: &self.theme,
+        })
     }
 
-    pub fn cursor_down(&mut self) {
-        self.cursor.pos.lnum += 1;
+    /// Work out how to draw the focused window in `rect`, returning the view without its
+    /// text, the lines it shows and the screen position of the cursor
+    fn window_view(
+        &mut self,
+        rect: Rect,
+        focused: bool,
+    ) -> (View<'static>, Range<usize>, (usize, usize)) {
+        self.wrangle_cursor();
+        let upper_limit = self
+            .buf()
+            .lines()
+            .len()
+            .min(self.term_y + self.view_height());
+        let gutter = self.gutter();
+        let wrap = self.wrap();
+        let (x, y) = match &wrap {
+            Some(wrap) => {
+                let (row, x) = self.cursor_row(wrap);
+                (gutter.width + x, row)
+            }
+            None => (
+                gutter.width + self.cursor_col() - self.term_x,
+                self.cursor.pos.lnum - self.term_y,
+            ),
+        };
+
+        let selection = match self.mode {
+            Mode::Visual if focused => {
+                if self.anchor < self.cursor.pos {
+                    Some((self.anchor, self.cursor.pos))
+                } else {
+                    Some((self.cursor.pos, self.anchor))
+                }
+            }
+            _ => None,
+        };
+
+        let highlights = self.highlights(self.term_y..upper_limit);
+        // Only visible brackets are matched, so a missing bracket doesn't scan the whole file
+        let pair = if self.option_bool("matchparen") && focused && self.mode != Mode::Command {
+            self.find_pair(self.cursor.pos, self.term_y..upper_limit)
+        } else {
+            None
+        };
+
+        // Windows with another window below show their name on the separator between them
+        let title = (rect.y + rect.height < self.term.height()).then(|| {
+            let buf = self.buf();
+            let name = if buf.filename.is_empty() {
+                "[No Name]"
+            } else {
+                &buf.filename
+            };
+            format!(" {}{} ", name, if buf.modified { " [+]" } else { "" })
+        });
+
+        let view = View {
+            rect,
+            title,
+            focused,
+            lines: &[],
+            first_line: self.term_y,
+            x_offset: self.term_x,
+            tabstop: self.tabstop(),
+            wrap,
+            gutter,
+            selection,
+            cursorline: self.option_bool("cursorline"),
+            pair,
+            list: self.listchars(),
+            highlights,
+        };
+
+        (view, self.term_y..upper_limit, (rect.x + x, rect.y + y))
     }
 
-    pub fn cursor_up(&mut self) {
-        self.cursor.pos.lnum = self.cursor.pos.lnum.saturating_sub(1);
+    /// Styled byte ranges for each line in `range`, empty when highlighting is off
+    fn highlights(&mut self, range: Range<usize>) -> Vec<Vec<(Range<usize>, Style)>> {
+        if !self.option_bool("syntax") {
+            return Vec::new();
+        }
+
+        match self.buffers[self.current].spans(range) {
+            Some((grammar, spans)) => spans
+                .into_iter()
+                .map(|spans| {
+                    spans
+                        .iter()
+                        .map(|s| {
+                            let style = self.theme.scope(grammar.scope_name(s.scope));
+                            (s.bytes.clone(), style)
+                        })
+                        .collect()
+                })
+                .collect(),
+            None => Vec::new(),
+        }
     }
 
-    pub fn line_start(&mut self) {
-        self.cursor.pos.index = 0;
+    fn status_line(&self, message: &str) -> (String, Option<Range<usize>>) {
+        let pending = match self.count {
+            Some(count) => format!("{}{}", count, self.pending),
+            None => self.pending.clone(),
+        };
+
+        let info = StatusInfo {
+            mode: self.mode.get_name(),
+            filename: &self.buf().filename,
+            modified: self.buf().modified,
+            readonly: self.option_bool("readonly"),
+            lnum: self.cursor.pos.lnum + 1,
+            col: self.cursor_col() + 1,
+            lines: self.buf().lines().len(),
+            filetype: self.option_str("filetype"),
+            fileformat: self.option_str("fileformat"),
+            pending: &pending,
+            message,
+        };
+
+        statusline::render(self.option_str("statusline"), &info, self.term.width())
     }
 
-    pub fn line_end(&mut self) {
-        self.cursor.pos.index = self.data[self.cursor.pos.lnum].len();
+    /// Write the current buffer to its file, asking for a name if it doesn't have one
+    pub fn save_file(&mut self) {
+        self.write_buffer(None, false, false, false);
+    }
+
+    /// Write the current buffer to `name`, or to its own file without one. An unnamed buffer
+    /// takes the name it is written to, as does any buffer with `rename`. With `mkdir`,
+    /// missing parent directories are created. Refuses to replace a file that isn't writable,
+    /// or to overwrite changes made to its file by something else, unless `force` is set
+    pub fn write_buffer(&mut self, name: Option<&str>, mkdir: bool, rename: bool, force: bool) {
+        let filename = self.buf().filename.clone();
+        let name = match name {
+            Some(name) => name.to_string(),
+            None if filename.is_empty() => {
+                // Ask for a name by starting the command for them
+                self.command_mode();
+                self.cmdline = if mkdir { "w ++p " } else { "w " }.to_string();
+                return;
+            }
+            None => filename.clone(),
+        };
+
+        if name == filename && self.option_bool("readonly") && !force {
+            self.error(format!("\"{}\" is read-only, :w! saves it anyway", name));
+            return;
+        }
+        if name == filename && !force {
+            let buf = self.buf_mut();
+            if buf.disk_changed || buf.disk.as_mut().is_some_and(|d| d.check(&filename)) {
+                buf.disk_changed = true;
+                self.error(format!(
+                    "\"{}\" has changed on disk since it was read, :w! overwrites it",
+                    name
+                ));
+                return;
+            }
+        }
+
+        let new = !Path::new(&name).exists();
+        match self.write_file(&name, mkdir, force) {
+            Ok(stamp) => {
+                if rename || filename.is_empty() {
+                    self.buf_mut().filename = name.clone();
+                    self.detect_filetype();
+                }
+                if name == self.buf().filename {
+                    let buf = self.buf_mut();
+                    buf.set_saved();
+                    buf.disk = Some(stamp);
+                    buf.disk_changed = false;
+                }
+
+                self.info(format!(
+                    "\"{}\"{}{} {}L written",
+                    name,
+                    if new { " [New]" } else { "" },
+                    self.file_tags(),
+                    self.buf().lines().len()
+                ));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && !mkdir => self.error(format!(
+                "Unable to save \"{}\": directory doesn't exist, :w ++p creates it",
+                name
+            )),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied && !force => self.error(format!(
+                "Unable to save \"{}\": {}, :w! saves it anyway",
+                name, e
+            )),
+            Err(e) => self.error(format!("Unable to save \"{}\": {}", name, e)),
+        }
+    }
+
+    fn write_file(&self, name: &str, mkdir: bool, force: bool) -> Result<FileStamp> {
+        let data = self.encode_buffer()?;
+        let options = WriteOptions {
+            fsync: self.option_bool("fsync"),
+            backup: self
+                .option_bool("backup")
+                .then(|| self.option_str("backupext")),
+            mkdir,
+            force,
+            private: false,
+        };
+
+        fileio::write_file(name, &data, &options)?;
+        Ok(FileStamp::new(name, &data))
+    }
+
+    /// The contents of the buffer set by `set_output` as they would be saved, to write to
+    /// stdout. Nothing if it has been closed
+    pub fn output_data(&mut self) -> Result<Vec<u8>> {
+        let Some(index) = self.buffers.iter().position(|b| Some(b.id) == self.output) else {
+            return Ok(Vec::new());
+        };
+        let current = std::mem::replace(&mut self.current, index);
+        let data = self.encode_buffer();
+        self.current = current;
+        data
+    }
+
+    // The current buffer as bytes to write to its file, with its line endings and encoding
+    fn encode_buffer(&self) -> Result<Vec<u8>> {
+        let newline = fileio::newline(self.option_str("fileformat"));
+        let mut text = self.buf().lines().join(newline);
+        if self.option_bool("endofline") {
+            text.push_str(newline);
+        }
+
+        let fenc = self.option_str("fileencoding");
+        encoding::encode(&text, fenc, self.option_bool("bomb")).map_err(|c| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("'{}' can't be written in {}", c, fenc),
+            )
+        })
+    }
+
+    fn wrangle_cursor(&mut self) {
+        // First make sure it is within the bounds of the text buffer
+        if self.cursor.pos.lnum >= self.buf().lines().len() {
+            self.cursor.pos.lnum = self.buf().lines().len() - 1;
+        }
+
+        if self.cursor.pos.index >= self.buf().lines()[self.cursor.pos.lnum].len() {
+            // Insert mode can go one character farther right
+            self.cursor.pos.index = self.buf().lines()[self.cursor.pos.lnum].len()
+                - if self.mode == Mode::Insert
+                    || self.buf().lines()[self.cursor.pos.lnum].is_empty()
+                {
+                    0
+                } else {
+                    1
+                };
+        }
+
+        // Now reposition the terminal window so that it contains the cursor, with `scrolloff`
+        // lines of context above and below
+        let height = self.view_height();
+        let lnum = self.cursor.pos.lnum;
+        let wrap = self.wrap();
+        let so = self.scrolloff();
+
+        if lnum.saturating_sub(so) < self.term_y {
+            self.term_y = lnum.saturating_sub(so);
+        }
+
+        let below = self.rows_below(wrap.as_ref(), so);
+        // Every line takes at least one row, which bounds how far down the view can start
+        self.term_y = self.term_y.max((lnum + 1 + below).saturating_sub(height));
+
+        if let Some(wrap) = wrap {
+            // Lines can take several rows, so scroll a line at a time until the cursor fits
+            self.term_x = 0;
+            while self.term_y < lnum && self.cursor_row(&wrap).0 + below >= height {
+                self.term_y += 1;
+            }
+            return;
+        }
+
+        let col = self.cursor_col();
+        let width = self.text_width();
+        let siso = self.option_int("sidescrolloff").max(0) as usize;
+        let siso = siso.min(width.saturating_sub(1) / 2);
+
+        if col < self.term_x + siso {
+            self.term_x = col.saturating_sub(siso);
+        } else if col + siso >= self.term_x + width {
+            self.term_x = col + siso + 1 - width;
+        }
+    }
+
+    /// `scrolloff`, limited so the cursor can still be kept in view
+    fn scrolloff(&self) -> usize {
+        let so = self.option_int("scrolloff").max(0) as usize;
+        so.min(self.view_height().saturating_sub(1) / 2)
+    }
+
+    /// Number of screen rows taken by a line
+    fn line_rows(&self, wrap: Option<&Wrap>, lnum: usize) -> usize {
+        wrap.map_or(1, |w| w.rows(&self.buf().lines()[lnum]).len())
+    }
+
+    /// Rows of text below the cursor's row, up to `limit`
+    fn rows_below(&self, wrap: Option<&Wrap>, limit: usize) -> usize {
+        let lnum = self.cursor.pos.lnum;
+        let mut below = match wrap {
+            Some(wrap) => {
+                let rows = wrap.rows(&self.buf().lines()[lnum]);
+                rows.len() - 1 - wrap.row_of(&rows, self.cursor.pos.index)
+            }
+            None => 0,
+        };
+
+        let mut l = lnum + 1;
+        while below < limit && l < self.buf().lines().len() {
+            below += self.line_rows(wrap, l);
+            l += 1;
+        }
+
+        below.min(limit)
+    }
+
+    /// Scroll so the cursor line is at the given place in the view, like vim's zz/zt/zb
+    pub fn view_cursor_at(&mut self, place: ViewPosition) {
+        self.wrangle_cursor();
+
+        let height = self.view_height();
+        let so = self.scrolloff();
+        let target = match place {
+            ViewPosition::Top => so,
+            ViewPosition::Center => height.saturating_sub(1) / 2,
+            ViewPosition::Bottom => height.saturating_sub(1 + so),
+        };
+
+        let wrap = self.wrap();
+        let lnum = self.cursor.pos.lnum;
+        let mut rows_above = match &wrap {
+            Some(wrap) => {
+                let rows = wrap.rows(&self.buf().lines()[lnum]);
+                wrap.row_of(&rows, self.cursor.pos.index)
+            }
+            None => 0,
+        };
+
+        self.term_y = lnum;
+        while self.term_y > 0
+            && rows_above + self.line_rows(wrap.as_ref(), self.term_y - 1) <= target
+        {
+            self.term_y -= 1;
+            rows_above += self.line_rows(wrap.as_ref(), self.term_y);
+        }
+    }
+
+    /// Scroll the view by whole lines without moving the cursor, unless it would leave the
+    /// view
+    pub fn scroll_vertical(&mut self, lines: isize) {
+        self.wrangle_cursor();
+
+        let last = self.buf().lines().len().saturating_sub(1);
+        self.term_y = self.term_y.saturating_add_signed(lines).min(last);
+
+        let so = self.scrolloff();
+        let wrap = self.wrap();
+
+        // The last line that fits completely in the view
+        let mut rows = 0;
+        let mut bottom = self.term_y;
+        for l in self.term_y..self.buf().lines().len() {
+            rows += self.line_rows(wrap.as_ref(), l);
+            if rows > self.view_height() {
+                break;
+            }
+            bottom = l;
+        }
+
+        let top = if self.term_y == 0 {
+            0
+        } else {
+            self.term_y + so
+        };
+        let bottom = if bottom == last {
+            last
+        } else {
+            bottom.saturating_sub(so)
+        };
+
+        self.cursor.pos.lnum = self.cursor.pos.lnum.min(bottom).max(top.min(bottom));
+    }
+
+    /// Scroll the view sideways without moving the cursor, unless it would leave the view.
+    /// Does nothing when lines wrap
+    pub fn scroll_horizontal(&mut self, cols: isize) {
+        if self.wrap().is_some() {
+            return;
+        }
+        self.wrangle_cursor();
+
+        let tabstop = self.tabstop();
+        let line = &self.buffers[self.current].lines()[self.cursor.pos.lnum];
+        let line_end = display_col(line, line.len(), tabstop).saturating_sub(1);
+        self.term_x = self.term_x.saturating_add_signed(cols).min(line_end);
+
+        let width = self.text_width();
+        let siso =
+            (self.option_int("sidescrolloff").max(0) as usize).min(width.saturating_sub(1) / 2);
+        let col = self
+            .cursor_col()
+            .max(self.term_x + siso)
+            .min(self.term_x + width.saturating_sub(1 + siso));
+
+        self.cursor.pos.index = index_at_col(line, col, tabstop);
+    }
+
+    /// Soft wrap settings, or None when `wrap` is off
+    fn wrap(&self) -> Option<Wrap> {
+        self.option_bool("wrap").then(|| Wrap {
+            width: self.text_width(),
+            linebreak: self.option_bool("linebreak"),
+            showbreak: self.option_str("showbreak").to_string(),
+            tabstop: self.tabstop(),
+        })
+    }
+
+    /// Screen row and column of the cursor relative to the text area when wrapping
+    fn cursor_row(&self, wrap: &Wrap) -> (usize, usize) {
+        let above = self.buf().lines()[self.term_y..self.cursor.pos.lnum]
+            .iter()
+            .map(|l| wrap.rows(l).len())
+            .sum::<usize>();
+
+        let rows = wrap.rows(&self.buf().lines()[self.cursor.pos.lnum]);
+        let r = wrap.row_of(&rows, self.cursor.pos.index);
+
+        (above + r, wrap.screen_col(&rows[r], self.cursor_col()))
+    }
+
+    /// Wrap settings if vertical motions should move by screen row rather than by line
+    fn display_motion(&self) -> Option<Wrap> {
+        if self.option_str("wrapmotion") == "display" {
+            self.wrap()
+        } else {
+            None
+        }
+    }
+
+    /// Move the cursor to the screen row above or below, keeping its column within the row
+    fn move_display_row(&mut self, wrap: &Wrap, down: bool) {
+        let pos = self.cursor.pos;
+        let rows = wrap.rows(&self.buf().lines()[pos.lnum]);
+        let r = wrap.row_of(&rows, pos.index);
+        let offset = self.cursor_col().saturating_sub(rows[r].start_col);
+
+        let (lnum, target) = if down && r + 1 < rows.len() {
+            (pos.lnum, rows[r + 1].clone())
+        } else if down && pos.lnum + 1 < self.buf().lines().len() {
+            (
+                pos.lnum + 1,
+                wrap.rows(&self.buf().lines()[pos.lnum + 1])[0].clone(),
+            )
+        } else if !down && r > 0 {
+            (pos.lnum, rows[r - 1].clone())
+        } else if !down && pos.lnum > 0 {
+            let rows = wrap.rows(&self.buf().lines()[pos.lnum - 1]);
+            (pos.lnum - 1, rows[rows.len() - 1].clone())
+        } else {
+            return;
+        };
+
+        let line = &self.buf().lines()[lnum];
+        let mut index = index_at_col(line, target.start_col + offset, self.tabstop());
+
+        // Stay on the target row rather than running into the next one
+        if index >= target.bytes.end && target.bytes.end < line.len() {
+            index = line[..target.bytes.end]
+                .char_indices()
+                .last()
+                .map_or(target.bytes.start, |(i, _)| i);
+        }
+
+        self.cursor.pos = Position::new(lnum, index);
+    }
+
+    /// Whitespace glyphs to draw, if `list` is on
+    fn listchars(&self) -> Option<ListChars> {
+        if self.option_bool("list") {
+            ListChars::parse(self.option_str("listchars")).ok()
+        } else {
+            None
+        }
+    }
+
+    fn gutter(&self) -> Gutter {
+        let absolute = self.option_bool("number");
+        let relative = self.option_bool("relativenumber");
+
+        let width = if absolute || relative {
+            let digits = self.buf().lines().len().to_string().len();
+            (digits + 1).max(self.option_int("numberwidth").max(2) as usize)
+        } else {
+            0
+        };
+
+        Gutter {
+            width,
+            relative,
+            absolute,
+            cursor_line: self.cursor.pos.lnum,
+        }
+    }
+
+    /// Width available for text once the gutter is drawn
+    fn text_width(&self) -> usize {
+        self.view_width().saturating_sub(self.gutter().width).max(1)
+    }
+
+    /// Display column of the cursor, which differs from its index when the line has tabs
+    fn cursor_col(&self) -> usize {
+        display_col(
+            &self.buf().lines()[self.cursor.pos.lnum],
+            self.cursor.pos.index,
+            self.tabstop(),
+        )
+    }
+
+    fn tabstop(&self) -> usize {
+        self.option_int("tabstop").max(1) as usize
+    }
+
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.term.resize(width, height)
+    }
+
+    pub fn insert_mode(&mut self) {
+        self.mode = Mode::Insert;
+    }
+
+    pub fn normal_mode(&mut self) {
+        self.mode = Mode::Normal;
+    }
+
+    pub fn visual_mode(&mut self) {
+        self.mode = Mode::Visual;
+        self.anchor = self.cursor.pos;
+    }
+
+    pub fn command_mode(&mut self) {
+        self.mode = Mode::Command;
+        self.cmdline.clear();
+    }
+
+    pub fn cmdline_push(&mut self, c: char) {
+        self.cmdline.push(c)
+    }
+
+    /// Returns false if the command line was already empty
+    pub fn cmdline_pop(&mut self) -> bool {
+        self.cmdline.pop().is_some()
+    }
+
+    pub fn take_cmdline(&mut self) -> String {
+        std::mem::take(&mut self.cmdline)
+    }
+
+    /// Record keys typed so far for a command that is waiting for more input
+    pub fn set_pending(&mut self, keys: &str) {
+        self.pending = keys.to_string();
+    }
+
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+        self.count = None;
+    }
+
+    pub fn push_count(&mut self, digit: usize) {
+        self.count = Some(
+            self.count
+                .unwrap_or(0)
+                .saturating_mul(10)
+                .saturating_add(digit),
+        );
+    }
+
+    /// Whether a count has been started, so `0` continues it rather than being a command
+    pub fn has_count(&self) -> bool {
+        self.count.is_some()
+    }
+
+    /// The count for the current command, 1 if none was given
+    pub fn take_count(&mut self) -> usize {
+        self.count.take().unwrap_or(1).max(1)
+    }
+
+    /// Show a message that disappears after `messagetime` milliseconds or a keypress
+    pub fn info(&mut self, msg: String) {
+        let timeout = self.message_time();
+        self.messages.push_timed(MessageLevel::Info, msg, timeout)
+    }
+
+    pub fn warn(&mut self, msg: String) {
+        let timeout = self.message_time();
+        self.messages
+            .push_timed(MessageLevel::Warning, msg, timeout)
+    }
+
+    /// Errors stay until a key is pressed so they can't be missed
+    pub fn error(&mut self, msg: String) {
+        self.messages.push_until_key(MessageLevel::Error, msg)
+    }
+
+    fn message_time(&self) -> Duration {
+        Duration::from_millis(self.option_int("messagetime").max(0) as u64)
+    }
+
+    /// Dismiss the shown message, called for each key event
+    pub fn dismiss_message(&mut self) {
+        self.messages.keypress()
+    }
+
+    // Look for a swap file left by an editor that crashed, or by one with the file open. The
+    // buffer doesn't write its own until it has been dealt with
+    fn find_swap(&mut self, index: usize) {
+        let buf = &self.buffers[index];
+        let Some(path) = swap::path(&buf.filename, buf.id, self.option_str("directory")) else {
+            return;
+        };
+        if !self.option_bool("swapfile") || !path.exists() {
+            return;
+        }
+
+        let note = match SwapFile::read(&path) {
+            Ok(swap) if swap.running() => format!(" (process {} may be editing it)", swap.pid),
+            Ok(_) => String::new(),
+            Err(e) => format!(" ({})", e),
+        };
+        self.warn(format!(
+            "Swap file \"{}\" found{}: :recover restores it, :swapdelete deletes it",
+            path.display(),
+            note
+        ));
+        self.buffers[index].swap.found = Some(path);
+    }
+
+    /// Replace the current buffer's text with the unsaved text in the swap file found for it
+    pub fn recover_swap(&mut self) -> std::result::Result<(), String> {
+        let path = self.buf().swap.found.clone();
+        let path = path.ok_or_else(|| "No swap file found for this buffer".to_string())?;
+        let swap = SwapFile::read(&path)
+            .map_err(|e| format!("Unable to read \"{}\": {}", path.display(), e))?;
+
+        let buf = self.buf_mut();
+        buf.replace_all(&swap.lines.join("\n"));
+        buf.swap.adopt();
+
+        self.cursor.pos = self.buf().clamp(self.cursor.pos);
+        self.info(format!(
+            "Recovered {}L from \"{}\", :w to keep them",
+            swap.lines.len(),
+            path.display()
+        ));
+        Ok(())
+    }
+
+    /// Delete the swap file found for the current buffer without recovering it
+    pub fn delete_swap(&mut self) -> std::result::Result<(), String> {
+        let path = self.buf().swap.found.clone();
+        let path = path.ok_or_else(|| "No swap file found for this buffer".to_string())?;
+        fs::remove_file(&path)
+            .map_err(|e| format!("Unable to delete \"{}\": {}", path.display(), e))?;
+
+        self.buf_mut().swap.found = None;
+        Ok(())
+    }
+
+    /// Write swap files for modified buffers that have had `updatecount` changes since theirs
+    /// were last written, or any change when `idle`, and remove those of unmodified buffers
+    pub fn update_swaps(&mut self, idle: bool) {
+        let enabled = self.option_bool("swapfile");
+        let directory = self.option_str("directory").to_string();
+        let count = match self.option_int("updatecount") {
+            _ if idle => 1,
+            count if count > 0 => count as usize,
+            _ => return,
+        };
+        let fsync = self.option_bool("fsync");
+
+        let mut errors = Vec::new();
+        for buf in &mut self.buffers {
+            if !enabled || !buf.modified {
+                buf.swap.remove();
+                continue;
+            }
+            if buf.swap.found.is_some() || !buf.swap.due(buf.changes, count) {
+                continue;
+            }
+
+            let Some(path) = swap::path(&buf.filename, buf.id, &directory) else {
+                continue;
+            };
+            if let Err(e) = buf.write_swap(path, fsync) {
+                let name = match buf.filename.as_str() {
+                    "" => format!("buffer {}", buf.id),
+                    name => format!("\"{}\"", name),
+                };
+                errors.push(format!("Unable to write swap file for {}: {}", name, e));
+            }
+        }
+
+        for e in errors {
+            self.error(e);
+        }
+    }
+
+    /// How long to wait for input before writing swap files that are out of date and looking
+    /// for files changed on disk, if there are any to do
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        let swaps = self.option_bool("swapfile")
+            && self
+                .buffers
+                .iter()
+                .any(|b| b.modified && b.swap.found.is_none() && b.swap.due(b.changes, 1));
+        let files = self
+            .buffers
+            .iter()
+            .any(|b| b.disk.is_some() && !b.disk_changed);
+
+        (swaps || files).then(|| Duration::from_millis(self.option_int("updatetime").max(0) as u64))
+    }
+
+    /// How long until the shown message expires, so the main loop can redraw then
+    pub fn message_timeout(&self) -> Option<Duration> {
+        self.messages.time_left()
+    }
+
+    /// Look up an option, preferring a value set locally for this buffer
+    pub fn option(&self, name: &str) -> Option<&OptionValue> {
+        self.buffers
+            .get(self.current)
+            .and_then(|b| b.local_options.get(name))
+            .or_else(|| self.config.options().get(name))
+    }
+
+    pub fn option_bool(&self, name: &str) -> bool {
+        self.option(name).is_some_and(|v| v.as_bool())
+    }
+
+    pub fn option_int(&self, name: &str) -> i64 {
+        self.option(name).map_or(0, |v| v.as_int())
+    }
+
+    pub fn option_str(&self, name: &str) -> &str {
+        self.option(name).map_or("", |v| v.as_str())
+    }
+
+    /// Buffer-scoped options are set for this buffer, and also globally unless `local` is set
+    pub fn set_option(
+        &mut self,
+        spec: &'static OptionSpec,
+        value: OptionValue,
+        local: bool,
+    ) -> std::result::Result<(), String> {
+        match (spec.scope, local) {
+            (OptionScope::Global, true) => {
+                return Err(format!("Not a buffer option: {}", spec.name));
+            }
+            (OptionScope::Global, false) => (),
+            (OptionScope::Buffer, _) => {
+                // Converting line endings changes what would be written
+                let converts = matches!(
+                    spec.name,
+                    "fileformat" | "endofline" | "fileencoding" | "bomb"
+                );
+                if converts && self.option(spec.name) != Some(&value) {
+                    self.buf_mut().set_converted();
+                }
+                let buf = self.buf_mut();
+                buf.local_options.set(spec, value.clone());
+                buf.filetype_options.retain(|name| *name != spec.name);
+            }
+        }
+
+        // Load the new theme first so a bad one leaves the options as they were
+        match spec.name {
+            "listchars" => {
+                ListChars::parse(value.as_str())?;
+            }
+            "theme" => self.theme = self.load_theme(value.as_str(), self.option_str("colors"))?,
+            "colors" => self.theme = self.load_theme(self.option_str("theme"), value.as_str())?,
+            _ => (),
+        }
+
+        if !local {
+            self.config.options_mut().set(spec, value);
+        }
+
+        if spec.name == "filetype" {
+            self.apply_filetype();
+        }
+
+        Ok(())
+    }
+
+    fn load_theme(&self, name: &str, colors: &str) -> std::result::Result<Theme, String> {
+        Theme::load(name, ColorMode::from_option(colors), self.config.dir())
+    }
+
+    /// Detect the filetype of the file being edited and apply its settings, followed by any
+    /// modeline in the file
+    pub fn detect_filetype(&mut self) {
+        let buf = self.buf();
+        if let Some(ft) = filetype::detect(&buf.filename, buf.lines(), &self.config) {
+            let spec = OptionSpec::lookup("filetype").unwrap();
+            self.buf_mut().local_options.set(spec, OptionValue::Str(ft));
+            self.apply_filetype();
+        }
+
+        // Setting the filetype applies its defaults, so it goes first to leave the rest of the
+        // modeline in force, as in `vim: et ft=make`
+        let args = filetype::modeline(self.buf().lines()).unwrap_or_default();
+        let (ft, rest): (Vec<_>, Vec<_>) = args.into_iter().partition(|arg| {
+            let name = arg.split(['=', '!', '?']).next().unwrap_or_default();
+            OptionSpec::lookup(name).is_some_and(|spec| spec.name == "filetype")
+        });
+        for arg in ft.iter().chain(&rest) {
+            if let Err(e) = commands::set_arg(self, arg, true) {
+                self.warn(format!("Error in modeline: {}", e));
+            }
+        }
+    }
+
+    /// Set the built-in and configured options for the buffer's current filetype, in place of
+    /// those the previous filetype set that haven't been set since
+    fn apply_filetype(&mut self) {
+        let ft = self.option_str("filetype").to_string();
+        let buf = self.buf_mut();
+        for name in std::mem::take(&mut buf.filetype_options) {
+            buf.local_options.unset(name);
+        }
+
+        let mut values = filetype::defaults(&ft)
+            .iter()
+            .filter_map(|(name, value)| {
+                let spec = OptionSpec::lookup(name)?;
+                Some((spec, spec.parse(value).ok()?))
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(options) = self.config.filetype_options(&ft) {
+            values.extend(
+                options
+                    .iter()
+                    .filter_map(|(name, value)| Some((OptionSpec::lookup(name)?, value.clone()))),
+            );
+        }
+
+        for (spec, value) in values {
+            if spec.scope == OptionScope::Buffer && spec.name != "filetype" {
+                let buf = self.buf_mut();
+                buf.local_options.set(spec, value);
+                buf.filetype_options.push(spec.name);
+            }
+        }
+
+        self.buf_mut().highlighter = self.config.grammar_for(&ft).map(Highlighter::new);
+    }
+
+    /// Turn a textobject from the bindings into one at the given position
+    pub fn resolve_textobject(&self, textobject: TextObject, pos: Position) -> TextObject {
+        // Repeated operators can leave the cursor past text they deleted
+        let pos = self.buf().clamp(pos);
+
+        match textobject {
+            TextObject::CancelOp => TextObject::None,
+            TextObject::Char(_) => TextObject::Char(pos),
+            TextObject::Line(_) => TextObject::Line(pos.lnum()),
+            TextObject::LineEnd(_, _) => self.get_lineend_textobject(pos),
+            TextObject::Word(_, _) => self.get_word_textobject(pos),
+            TextObject::Pair(_, _) => self.get_pair_textobject(pos),
+            TextObject::None => TextObject::None,
+            TextObject::Selection(p) => TextObject::Selection(p),
+        }
+    }
+
+    pub fn get_word_textobject(&self, pos: Position) -> TextObject {
+        let word_chars = self.option_str("wordchars");
+
+        TextObject::Word(
+            pos,
+            match self.buf().lines()[pos.lnum][pos.index..].split_once(|c| word_chars.contains(c)) {
+                Some((w, _)) => w.len(),
+                None => self.buf().lines()[pos.lnum].len() - pos.index,
+            },
+        )
+    }
+
+    pub fn get_lineend_textobject(&self, pos: Position) -> TextObject {
+        TextObject::LineEnd(pos, self.buf().lines()[pos.lnum].len() - pos.index)
+    }
+
+    /// From the bracket under or before `pos` to its match, in either direction
+    pub fn get_pair_textobject(&self, pos: Position) -> TextObject {
+        match self.find_pair(pos, 0..self.buf().lines().len()) {
+            Some((a, b)) => {
+                let (start, end) = if a < b { (a, b) } else { (b, a) };
+                let len = self.buf().lines()[end.lnum][end.index..]
+                    .chars()
+                    .next()
+                    .map_or(1, |c| c.len_utf8());
+                TextObject::Pair(start, Position::new(end.lnum, end.index + len))
+            }
+            None => TextObject::None,
+        }
+    }
+
+    /// Bracket pairs from the `matchpairs` option, as (open, close)
+    fn match_pairs(&self) -> Vec<(char, char)> {
+        self.option_str("matchpairs")
+            .split(',')
+            .filter_map(|pair| {
+                let mut chars = pair.chars();
+                match (chars.next(), chars.next(), chars.next(), chars.next()) {
+                    (Some(open), Some(':'), Some(close), None) => Some((open, close)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Find the bracket under `pos`, or failing that the one just before it, and its match.
+    /// Only lines in `search` are looked through for the match
+    fn find_pair(&self, pos: Position, search: Range<usize>) -> Option<(Position, Position)> {
+        let pairs = self.match_pairs();
+        let line = self.buf().lines().get(pos.lnum)?;
+        let before = line
+            .get(..pos.index)
+            .and_then(|l| l.char_indices().next_back())
+            .map(|(i, _)| i);
+
+        for index in [Some(pos.index), before].into_iter().flatten() {
+            let Some(ch) = line.get(index..).and_then(|l| l.chars().next()) else {
+                continue;
+            };
+
+            if let Some(&(open, close)) = pairs.iter().find(|(o, c)| *o == ch || *c == ch) {
+                let at = Position::new(pos.lnum, index);
+                return self
+                    .find_match(at, open, close, ch == open, search)
+                    .map(|m| (at, m));
+            }
+        }
+
+        None
+    }
+
+    /// Scan from the bracket at `from` for its match, skipping nested pairs
+    fn find_match(
+        &self,
+        from: Position,
+        open: char,
+        close: char,
+        forward: bool,
+        search: Range<usize>,
+    ) -> Option<Position> {
+        let (inc, dec) = if forward {
+            (open, close)
+        } else {
+            (close, open)
+        };
+        let mut depth = 0;
+
+        let mut check = |lnum: usize, index: usize, ch: char| {
+            if ch == inc {
+                depth += 1;
+            } else if ch == dec {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(Position::new(lnum, index));
+                }
+            }
+            None
+        };
+
+        if forward {
+            for lnum in from.lnum..search.end.min(self.buf().lines().len()) {
+                let start = if lnum == from.lnum { from.index } else { 0 };
+                let line = &self.buf().lines()[lnum][start..];
+                if let Some(m) = line
+                    .char_indices()
+                    .find_map(|(i, c)| check(lnum, start + i, c))
+                {
+                    return Some(m);
+                }
+            }
+        } else {
+            for lnum in (search.start..from.lnum + 1).rev() {
+                let line = &self.buf().lines()[lnum];
+                let end = if lnum == from.lnum {
+                    from.index + inc.len_utf8()
+                } else {
+                    line.len()
+                };
+                if let Some(m) = line[..end]
+                    .char_indices()
+                    .rev()
+                    .find_map(|(i, c)| check(lnum, i, c))
+                {
+                    return Some(m);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Remove spaces and tabs from the ends of lines, returning how many lines changed
+    pub fn strip_trailing_whitespace(&mut self) -> usize {
+        let mut stripped = 0;
+
+        for lnum in 0..self.buf().lines().len() {
+            let line = &self.buf().lines()[lnum];
+            let len = line.trim_end_matches([' ', '\t']).len();
+            if len < line.len() {
+                let end = Position::new(lnum, line.len());
+                self.buf_mut().edit(Position::new(lnum, len), end, "");
+                stripped += 1;
+            }
+        }
+
+        stripped
+    }
+
+    /// Move the cursor to the bracket matching the one under it
+    pub fn jump_to_pair(&mut self) {
+        if let Some((_, m)) = self.find_pair(self.cursor.pos, 0..self.buf().lines().len()) {
+            self.cursor.pos = m;
+        }
+    }
+
+    pub fn insert_text(&mut self, pos: Position, text: &str) {
+        self.cursor.pos = self.buf_mut().edit(pos, pos, text);
+    }
+
+    pub fn replace(&mut self, txt_obj: TextObject, text: &str) {
+        if txt_obj.is_none() {
+            return;
+        }
+
+        let (start, end) = if let TextObject::Selection(_) = txt_obj {
+            txt_obj.get_selection_bounds(self.anchor)
+        } else {
+            txt_obj.get_bounds()
+        };
+
+        self.normal_mode();
+        // Nothing to replace, e.g. `r` on an empty line
+        let (start, end) = self.buf().clamp_range(start, end);
+        if start == end {
+            return;
+        }
+        self.buf_mut().edit(start, end, text);
+    }
+
+    /// Replace `count` characters from the cursor with `c` as one change, leaving the cursor
+    /// on the last of them. Like vim, nothing is replaced if the line doesn't have that many
+    pub fn replace_chars(&mut self, c: char, count: usize) {
+        let pos = self.buf().clamp(self.cursor.pos);
+        let line = &self.buf().lines()[pos.lnum];
+        let chars = line[pos.index..].chars().take(count).collect::<Vec<_>>();
+        if chars.len() < count {
+            return;
+        }
+
+        let end = pos.index + chars.iter().map(|c| c.len_utf8()).sum::<usize>();
+        let text = c.to_string().repeat(count);
+        self.buf_mut()
+            .edit(pos, Position::new(pos.lnum, end), &text);
+        self.cursor.pos = Position::new(pos.lnum, pos.index + (count - 1) * c.len_utf8());
+    }
+
+    pub fn insert_newline(&mut self) {
+        let pos = self.cursor.pos;
+        self.cursor.pos = self.buf_mut().edit(pos, pos, "\n");
+    }
+
+    pub fn delete_newline(&mut self, lnum: usize) {
+        if lnum + 1 >= self.buf().lines().len() {
+            return;
+        }
+
+        let end_index = self.buf().lines()[lnum].len();
+        let end = Position::new(lnum, end_index);
+        self.buf_mut().edit(end, Position::new(lnum + 1, 0), "");
+
+        self.cursor.pos = end;
+    }
+
+    pub fn delete(&mut self, txt_obj: TextObject) {
+        if txt_obj.is_none() {
+            return;
+        }
+
+        let (start, end) = if let TextObject::Selection(_) = txt_obj {
+            if self.anchor < self.cursor.pos {
+                self.cursor.pos = self.anchor;
+            }
+            txt_obj.get_selection_bounds(self.anchor)
+        } else {
+            txt_obj.get_bounds()
+        };
+
+        self.normal_mode();
+        self.buf_mut().edit(start, end, "");
+    }
+
+    /// Start a new undo step, so the next change is undone separately from earlier ones
+    pub fn break_undo(&mut self) {
+        self.buf_mut().break_undo();
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.buf_mut().undo() {
+            Some(pos) => {
+                self.cursor.pos = pos;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.buf_mut().redo() {
+            Some(pos) => {
+                self.cursor.pos = pos;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_mark(&mut self, name: char) {
+        let pos = self.cursor.pos;
+        self.buf_mut().set_mark(name, pos);
+    }
+
+    /// Move the cursor to a mark, returning false if it isn't set
+    pub fn goto_mark(&mut self, name: char) -> bool {
+        match self.buf().mark(name) {
+            Some(pos) => {
+                self.cursor.pos = self.buf().clamp(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The marks of the current buffer for :marks, like `a 12:4`
+    pub fn list_marks(&self) -> Vec<String> {
+        self.buf()
+            .marks()
+            .map(|(name, pos)| format!("{} {}:{}", name, pos.lnum + 1, pos.index + 1))
+            .collect()
+    }
+
+    pub fn insert_bind(&self, key: &str) -> Option<InsertAction> {
+        self.config.insert(key)
+    }
+
+    pub fn normal_bind(&self, key: &str) -> Option<NormalAction> {
+        self.config.normal(key)
+    }
+
+    pub fn visual_bind(&self, key: &str) -> Option<VisualAction> {
+        self.config.visual(key)
+    }
+
+    pub fn textobject_bind(&self, key: &str) -> Option<TextObject> {
+        self.config.textobject(key)
+    }
+
+    pub fn view_bind(&self, key: &str) -> Option<ViewAction> {
+        self.config.view(key)
+    }
+
+    pub fn window_bind(&self, key: &str) -> Option<WindowAction> {
+        self.config.window(key)
+    }
+
+    pub fn backspace(&mut self, pos: Position) {
+        let pos = self.buf().clamp(pos);
+        let prev = self.buf().lines()[pos.lnum][..pos.index]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i);
+        self.buf_mut().edit(Position::new(pos.lnum, prev), pos, "");
+        self.cursor.pos.index = prev;
+    }
+
+    /// Delete the character after `pos` without leaving insert mode, joining the next line
+    /// at the end of a line
+    pub fn delete_forward(&mut self, pos: Position) {
+        let pos = self.buf().clamp(pos);
+        let line = &self.buf().lines()[pos.lnum];
+
+        match line[pos.index..].chars().next() {
+            Some(c) => {
+                let end = Position::new(pos.lnum, pos.index + c.len_utf8());
+                self.buf_mut().edit(pos, end, "");
+            }
+            None => {
+                self.delete_newline(pos.lnum);
+                self.cursor.pos = pos;
+            }
+        }
+    }
+
+    pub fn indent(&mut self) {
+        let indent = if self.option_bool("expandtab") {
+            " ".repeat(self.option_int("tabstop").max(0) as usize)
+        } else {
+            "\t".to_string()
+        };
+        self.insert_text(self.cursor.pos, &indent);
+    }
+
+    pub fn cursor_right(&mut self) {
+        self.cursor.pos.index += 1;
+    }
+
+    pub fn cursor_left(&mut self) {
+        self.cursor.pos.index = self.cursor.pos.index.saturating_sub(1);
+    }
+
+    pub fn cursor_down(&mut self) {
+        match self.display_motion() {
+            Some(wrap) => self.move_display_row(&wrap, true),
+            None => self.cursor.pos.lnum += 1,
+        }
+    }
+
+    pub fn cursor_up(&mut self) {
+        match self.display_motion() {
+            Some(wrap) => self.move_display_row(&wrap, false),
+            None => self.cursor.pos.lnum = self.cursor.pos.lnum.saturating_sub(1),
+        }
+    }
+
+    pub fn line_start(&mut self) {
+        self.cursor.pos.index = 0;
+    }
+
+    pub fn line_end(&mut self) {
+        let lnum = self.cursor.pos.lnum.min(self.buf().lines().len() - 1);
+        self.cursor.pos.index = self.buf().lines()[lnum].len();
+    }
+}
+
+/// Set the buffer options that say how its file was stored, so it is written back the same way
+fn set_file_options(options: &mut Options, file: &FileData) {
+    let values = [
+        ("fileformat", OptionValue::Str(file.fileformat.to_string())),
+        ("endofline", OptionValue::Bool(file.eol)),
+        ("fileencoding", OptionValue::Str(file.encoding.to_string())),
+        ("bomb", OptionValue::Bool(file.bom)),
+    ];
+    for (name, value) in values {
+        options.set(OptionSpec::lookup(name).unwrap(), value);
+    }
+    // Only ever set, so it doesn't hide a global readonly from -R
+    if file.readonly {
+        options.set(
+            OptionSpec::lookup("readonly").unwrap(),
+            OptionValue::Bool(true),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    use super::*;
+    use crate::modes;
+
+    fn editor(line: &str) -> EditorState {
+        let config = Config::init(true).unwrap();
+        let mut state = EditorState::new(Vec::new(), Term::headless(80, 24), config);
+        state.insert_text(Position::new(0, 0), line);
+        state.normal_mode();
+        state.cursor.pos = Position::new(0, 0);
+        state
+    }
+
+    fn keys(state: &mut EditorState, keys: &str) {
+        for c in keys.chars() {
+            let ke = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+            modes::process_key_event(ke, state).unwrap();
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let mut state = editor("abcdefghijklmnop");
+        keys(&mut state, "3i");
+        assert_eq!(state.cursor.pos.index, 3);
+        assert!(!state.has_count());
+
+        // 0 only continues a count, it doesn't start one
+        keys(&mut state, "0i");
+        assert_eq!(state.cursor.pos.index, 4);
+        keys(&mut state, "10i");
+        assert_eq!(state.cursor.pos.index, 14);
+
+        keys(&mut state, "12");
+        assert_eq!(state.take_count(), 12);
+        assert_eq!(state.take_count(), 1);
+    }
+
+    #[test]
+    fn replace_chars() {
+        let mut state = editor("abcdef");
+        state.replace_chars('x', 3);
+        assert_eq!(state.buf().lines(), ["xxxdef"]);
+        assert_eq!(state.cursor.pos.index, 2);
+
+        // Too few characters left replaces nothing
+        state.replace_chars('y', 9);
+        assert_eq!(state.buf().lines(), ["xxxdef"]);
+
+        let mut state = editor("\u{e9}\u{e9}a");
+        state.replace_chars('Z', 2);
+        assert_eq!(state.buf().lines(), ["ZZa"]);
+        assert_eq!(state.cursor.pos.index, 1);
+    }
+
+    #[test]
+    fn filetype_options_are_replaced() {
+        let mut state = editor("");
+        let set = |state: &mut EditorState, name, value: &str| {
+            let spec = OptionSpec::lookup(name).unwrap();
+            state
+                .set_option(spec, spec.parse(value).unwrap(), true)
+                .unwrap();
+        };
+
+        set(&mut state, "filetype", "make");
+        assert_eq!(state.option_str("commentstring"), "# %s");
+        assert!(!state.option_bool("expandtab"));
+
+        // What the user set is kept, what make set goes back to the global value
+        set(&mut state, "tabstop", "3");
+        set(&mut state, "filetype", "rust");
+        assert_eq!(state.option_int("tabstop"), 3);
+        assert!(state.option_bool("expandtab"));
+        assert_eq!(state.option_str("commentstring"), "// %s");
+
+        set(&mut state, "filetype", "json");
+        assert_eq!(state.option_int("tabstop"), 2);
+        assert_eq!(
+            state.option_str("commentstring"),
+            state
+                .config
+                .options()
+                .get("commentstring")
+                .unwrap()
+                .as_str()
+        );
     }
 }
@@ -0,0 +1,200 @@
+use std::fmt::Write;
+use std::str;
+
+/// Encodings files can be read and written in, as for the fileencoding option. "escaped" is
+/// UTF-8 where bytes that aren't valid UTF-8 are shown as `\xNN`, so any file can be edited
+/// and saved without changing the bytes that weren't touched
+pub(crate) const ENCODINGS: &[&str] = &["utf-8", "utf-16le", "utf-16be", "latin1", "escaped"];
+
+/// The byte order mark for an encoding, empty if it doesn't have one
+fn bom(encoding: &str) -> &'static [u8] {
+    match encoding {
+        "utf-8" | "escaped" => b"\xef\xbb\xbf",
+        "utf-16le" => b"\xff\xfe",
+        "utf-16be" => b"\xfe\xff",
+        _ => b"",
+    }
+}
+
+/// The encoding named, as its entry in `ENCODINGS`
+pub(crate) fn lookup(name: &str) -> Option<&'static str> {
+    ENCODINGS.iter().copied().find(|e| *e == name)
+}
+
+/// Guess the encoding of a file from its byte order mark
+pub(crate) fn detect_bom(bytes: &[u8]) -> Option<&'static str> {
+    ["utf-8", "utf-16le", "utf-16be"]
+        .into_iter()
+        .find(|e| bytes.starts_with(bom(e)))
+}
+
+/// Decode a file's contents, or None if they aren't valid in `encoding`. A byte order mark
+/// for the encoding is left out of the text, and reported along with it
+pub(crate) fn decode(bytes: &[u8], encoding: &str) -> Option<(String, bool)> {
+    let mark = bom(encoding);
+    let has_bom = !mark.is_empty() && bytes.starts_with(mark);
+    let bytes = if has_bom { &bytes[mark.len()..] } else { bytes };
+
+    let text = match encoding {
+        "utf-8" => String::from_utf8(bytes.to_vec()).ok()?,
+        "utf-16le" => decode_utf16(bytes, u16::from_le_bytes)?,
+        "utf-16be" => decode_utf16(bytes, u16::from_be_bytes)?,
+        "latin1" => bytes.iter().map(|&b| b as char).collect(),
+        "escaped" => decode_escaped(bytes),
+        _ => return None,
+    };
+
+    Some((text, has_bom))
+}
+
+/// Encode text to be written in `encoding`, starting with a byte order mark if `with_bom` is
+/// set. Fails with the first character the encoding can't represent
+pub(crate) fn encode(text: &str, encoding: &str, with_bom: bool) -> Result<Vec<u8>, char> {
+    let mut bytes = if with_bom {
+        bom(encoding).to_vec()
+    } else {
+        Vec::new()
+    };
+
+    match encoding {
+        "utf-16le" => bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes)),
+        "utf-16be" => bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes)),
+        "latin1" => {
+            for c in text.chars() {
+                bytes.push(u8::try_from(c).map_err(|_| c)?);
+            }
+        }
+        "escaped" => encode_escaped(text, &mut bytes),
+        _ => bytes.extend_from_slice(text.as_bytes()),
+    }
+
+    Ok(bytes)
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Option<String> {
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+
+    let units = bytes.chunks_exact(2).map(|c| unit([c[0], c[1]]));
+    char::decode_utf16(units).collect::<Result<_, _>>().ok()
+}
+
+// Valid UTF-8 is kept as it is and other bytes become `\xNN`. A backslash that would otherwise
+// be read back as the start of an escape is doubled, so encoding the text gives back exactly
+// the bytes it was decoded from
+fn decode_escaped(bytes: &[u8]) -> String {
+    let mut parts = utf8_parts(bytes).into_iter().peekable();
+
+    let mut text = String::new();
+    while let Some(part) = parts.next() {
+        match part {
+            Ok('\\') => {
+                text.push('\\');
+                if matches!(parts.peek(), Some(Ok('x' | '\\') | Err(_))) {
+                    text.push('\\');
+                }
+            }
+            Ok(c) => text.push(c),
+            Err(b) => write!(text, "\\x{:02x}", b).unwrap(),
+        }
+    }
+
+    text
+}
+
+// The characters of `bytes` where it is valid UTF-8, and the bytes where it isn't
+fn utf8_parts(mut bytes: &[u8]) -> Vec<Result<char, u8>> {
+    let mut parts = Vec::new();
+    while !bytes.is_empty() {
+        let (valid, invalid) = match str::from_utf8(bytes) {
+            Ok(_) => (bytes.len(), 0),
+            Err(e) => {
+                let valid = e.valid_up_to();
+                (valid, e.error_len().unwrap_or(bytes.len() - valid))
+            }
+        };
+
+        let text = str::from_utf8(&bytes[..valid]).unwrap();
+        parts.extend(text.chars().map(Ok));
+        parts.extend(bytes[valid..valid + invalid].iter().map(|&b| Err(b)));
+        bytes = &bytes[valid + invalid..];
+    }
+    parts
+}
+
+fn encode_escaped(text: &str, bytes: &mut Vec<u8>) {
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        bytes.extend_from_slice(&rest.as_bytes()[..i]);
+        let after = &rest[i + 1..];
+
+        let escaped = after
+            .strip_prefix('x')
+            .and_then(|hex| hex.get(..2))
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        rest = match escaped {
+            Some(b) => {
+                bytes.push(b);
+                &after[3..]
+            }
+            None => {
+                bytes.push(b'\\');
+                after.strip_prefix('\\').unwrap_or(after)
+            }
+        };
+    }
+
+    bytes.extend_from_slice(rest.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) {
+        let (text, bom) = decode(bytes, "escaped").unwrap();
+        assert_eq!(encode(&text, "escaped", bom).unwrap(), bytes, "{:?}", text);
+    }
+
+    #[test]
+    fn escaped_decoding() {
+        assert_eq!(decode_escaped(b"a\xffb"), "a\\xffb");
+        assert_eq!(decode_escaped("é\\n".as_bytes()), "é\\n");
+        // Text that looks like an escape has its backslash doubled
+        assert_eq!(decode_escaped(b"\\x41"), "\\\\x41");
+        assert_eq!(decode_escaped(b"a\\"), "a\\");
+        assert_eq!(decode_escaped(b"\\\\"), "\\\\\\");
+        assert_eq!(decode_escaped(b"\\\xff"), "\\\\\\xff");
+        assert_eq!(decode_escaped(b"\xe2\x82"), "\\xe2\\x82");
+    }
+
+    #[test]
+    fn escaped_encoding() {
+        let mut bytes = Vec::new();
+        encode_escaped("\\x41\\\\x41\\xzz\\x4", &mut bytes);
+        assert_eq!(bytes, b"A\\x41\\xzz\\x4");
+    }
+
+    #[test]
+    fn escaped_round_trips() {
+        for bytes in [
+            &b""[..],
+            b"plain",
+            b"a\xffb",
+            b"\\x41",
+            b"\\\\x41",
+            b"trailing\\",
+            b"\\\xff",
+            b"\\\\\xfe\\",
+            b"\\x\xff",
+            b"\xe2\x82",
+            b"\xef\xbb\xbfbom",
+            "é\\é".as_bytes(),
+        ] {
+            round_trip(bytes);
+        }
+    }
+}
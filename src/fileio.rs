@@ -0,0 +1,502 @@
+use std::fs::{self, File, OpenOptions};
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+use crate::encoding;
+
+/// The contents of a file split into lines, and how they were stored
+pub(crate) struct FileData {
+    pub lines: Vec<String>,
+    pub fileformat: &'static str, // "unix", "dos" or "mac", as for the fileformat option
+    pub eol: bool,                // Whether the last line ended with a newline
+    pub encoding: &'static str,   // As for the fileencoding option
+    pub bom: bool,                // Whether it started with a byte order mark
+    pub stamp: Option<FileStamp>, // None when it wasn't read from a file
+    pub readonly: bool,           // The file exists but can't be written
+}
+
+/// What a file was like when it was read or written, to tell when something else changes it
+#[derive(Clone, Copy)]
+pub(crate) struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl FileStamp {
+    /// The stamp for file `name` just read or written with `data`
+    pub fn new(name: &str, data: &[u8]) -> FileStamp {
+        FileStamp {
+            modified: fs::metadata(name).and_then(|m| m.modified()).ok(),
+            len: data.len() as u64,
+            hash: hash(data),
+        }
+    }
+
+    /// Whether the file's contents are different now. Only the contents count, so a file
+    /// that was just touched isn't changed, and its new time is remembered to save reading it
+    /// again. A file that has gone isn't counted as changed either
+    pub fn check(&mut self, name: &str) -> bool {
+        let Ok(meta) = fs::metadata(name) else {
+            return false;
+        };
+        let modified = meta.modified().ok();
+        if modified == self.modified && meta.len() == self.len {
+            return false;
+        }
+
+        match fs::read(name) {
+            Ok(data) if data.len() as u64 == self.len && hash(&data) == self.hash => {
+                self.modified = modified;
+                false
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Read a file, or None if it doesn't exist yet. `encodings` is a comma separated list of
+/// encodings to try in turn, as for the fileencodings option, where "ucs-bom" picks the one
+/// given by a byte order mark
+pub(crate) fn read_file(name: &str, encodings: &str) -> Result<Option<FileData>> {
+    let bytes = match fs::read(name) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut file = decode(&bytes, encodings)?;
+    file.stamp = Some(FileStamp::new(name, &bytes));
+    file.readonly = !writable(Path::new(name));
+    Ok(Some(file))
+}
+
+/// Read all of stdin, as for `read_file`
+pub(crate) fn read_stdin(encodings: &str) -> Result<FileData> {
+    let mut bytes = Vec::new();
+    io::stdin().read_to_end(&mut bytes)?;
+    decode(&bytes, encodings)
+}
+
+fn decode(bytes: &[u8], encodings: &str) -> Result<FileData> {
+    let decoded = encodings.split(',').find_map(|name| {
+        let encoding = match name.trim() {
+            "ucs-bom" => encoding::detect_bom(bytes)?,
+            name => encoding::lookup(name)?,
+        };
+        let (text, bom) = encoding::decode(bytes, encoding)?;
+        Some((text, encoding, bom))
+    });
+
+    let Some((text, encoding, bom)) = decoded else {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("not valid in any of {}", encodings),
+        ));
+    };
+
+    let (lines, fileformat, eol) = split_lines(&text);
+    Ok(FileData {
+        lines,
+        fileformat,
+        eol,
+        encoding,
+        bom,
+        stamp: None,
+        readonly: false,
+    })
+}
+
+/// Split text into lines. Lines all ending in CRLF are read as dos, lines ending in a lone CR
+/// with no LF anywhere as mac, and anything else as unix, leaving any stray CRs in the text
+fn split_lines(text: &str) -> (Vec<String>, &'static str, bool) {
+    let lf = text.matches('\n').count();
+    let (fileformat, newline) = if lf > 0 && text.matches("\r\n").count() == lf {
+        ("dos", "\r\n")
+    } else if lf == 0 && text.contains('\r') {
+        ("mac", "\r")
+    } else {
+        ("unix", "\n")
+    };
+
+    let mut lines = text.split(newline).map(String::from).collect::<Vec<_>>();
+    let eol = lines.len() > 1 && lines.last().is_some_and(|l| l.is_empty());
+    if eol {
+        lines.pop();
+    }
+
+    (lines, fileformat, eol)
+}
+
+/// The line ending written for a fileformat option value
+pub(crate) fn newline(fileformat: &str) -> &'static str {
+    match fileformat {
+        "dos" => "\r\n",
+        "mac" => "\r",
+        _ => "\n",
+    }
+}
+
+/// How to write a file, from the options
+pub(crate) struct WriteOptions<'a> {
+    pub fsync: bool,             // Flush to disk before reporting success
+    pub backup: Option<&'a str>, // Extension of a copy of the old file to keep
+    pub mkdir: bool,             // Create missing parent directories
+    pub force: bool,             // Replace a file even if it isn't writable or atomically
+    pub private: bool,           // Make a new file readable only by its owner
+}
+
+/// Write `data` to a file without ever leaving it half written. The data goes to a temporary
+/// file next to it, which is renamed over the original once complete, keeping the original's
+/// permissions and owner. When that isn't possible, because the directory isn't writable, the
+/// owner can't be kept or the file has other hard links the rename would split it from, the
+/// file is only overwritten in place with `force`, as that can leave it half written. A file
+/// that isn't writable is also only replaced with `force`, as renaming over it only needs the
+/// directory to be
+pub(crate) fn write_file(name: &str, data: &[u8], options: &WriteOptions) -> Result<()> {
+    let path = Path::new(name);
+    if options.mkdir {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    // Write through symlinks rather than replacing them with a file
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let original = fs::metadata(&target).ok();
+    if original.is_some() && !options.force && !writable(&target) {
+        return Err(Error::new(ErrorKind::PermissionDenied, "file is read-only"));
+    }
+
+    if let (Some(ext), Some(_)) = (options.backup, &original) {
+        let mut backup = target.clone().into_os_string();
+        backup.push(ext);
+        fs::copy(&target, backup)?;
+    }
+
+    let in_place = |why: &str| match options.force {
+        true => write_in_place(&target, data, options.fsync),
+        false => Err(Error::new(ErrorKind::PermissionDenied, why)),
+    };
+    if original.as_ref().is_some_and(hard_linked) {
+        return in_place("file has other hard links");
+    }
+
+    let temp = temp_path(&target);
+    match write_temp(&temp, data, original.as_ref(), options) {
+        Ok(true) => (),
+        Ok(false) => {
+            fs::remove_file(&temp)?;
+            return in_place("file's owner can't be kept");
+        }
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && original.is_some() => {
+            fs::remove_file(&temp).unwrap_or_default();
+            return in_place("directory isn't writable");
+        }
+        Err(e) => {
+            fs::remove_file(&temp).unwrap_or_default();
+            return Err(e);
+        }
+    }
+
+    if let Err(e) = fs::rename(&temp, &target) {
+        fs::remove_file(&temp).unwrap_or_default();
+        return Err(e);
+    }
+
+    if options.fsync {
+        sync_dir(&target)?;
+    }
+
+    Ok(())
+}
+
+// Whether an existing file is meant to be written: it has write permission, and can be opened
+// for writing, which is checked without changing it
+fn writable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| !m.permissions().readonly())
+        && OpenOptions::new().write(true).open(path).is_ok()
+}
+
+// Whether other names share the file, which would keep the old contents after a rename
+#[cfg(unix)]
+fn hard_linked(meta: &fs::Metadata) -> bool {
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn hard_linked(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+// A hidden file in the same directory, so the rename doesn't cross filesystems
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
+
+// Write the temporary file and give it the original's permissions and owner. It is created
+// with those permissions so the data is never readable by anyone the original hid it from.
+// Returns false if the owner couldn't be kept
+fn write_temp(
+    temp: &Path,
+    data: &[u8],
+    original: Option<&fs::Metadata>,
+    options: &WriteOptions,
+) -> Result<bool> {
+    let mut open = OpenOptions::new();
+    open.write(true).create_new(true);
+    #[cfg(unix)]
+    match original {
+        Some(original) => {
+            open.mode(original.mode() & 0o777);
+        }
+        None if options.private => {
+            open.mode(0o600);
+        }
+        None => (),
+    }
+
+    let mut f = open.open(temp)?;
+    f.write_all(data)?;
+    if options.fsync {
+        f.sync_all()?;
+    }
+
+    let Some(original) = original else {
+        return Ok(true);
+    };
+    fs::set_permissions(temp, original.permissions())?;
+
+    #[cfg(unix)]
+    {
+        let written = f.metadata()?;
+        if written.uid() != original.uid() || written.gid() != original.gid() {
+            let owner = std::os::unix::fs::chown(temp, Some(original.uid()), Some(original.gid()));
+            return Ok(owner.is_ok());
+        }
+    }
+
+    Ok(true)
+}
+
+fn write_in_place(target: &Path, data: &[u8], fsync: bool) -> Result<()> {
+    let mut f = OpenOptions::new().write(true).truncate(true).open(target)?;
+    f.write_all(data)?;
+    if fsync {
+        f.sync_all()?;
+    }
+    Ok(())
+}
+
+// Make the rename itself durable
+#[cfg(unix)]
+fn sync_dir(target: &Path) -> Result<()> {
+    match target.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_target: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn check(text: &str, lines: &[&str], fileformat: &str, eol: bool) {
+        assert_eq!(
+            split_lines(text),
+            (
+                lines.iter().map(|l| l.to_string()).collect(),
+                fileformat,
+                eol
+            )
+        );
+    }
+
+    // Writing the lines back the way they were read gives the same text
+    fn round_trip(text: &str) {
+        let (lines, fileformat, eol) = split_lines(text);
+        let mut written = lines.join(newline(fileformat));
+        if eol {
+            written.push_str(newline(fileformat));
+        }
+        assert_eq!(written, text);
+    }
+
+    #[test]
+    fn line_endings() {
+        check("a\nb\n", &["a", "b"], "unix", true);
+        check("a\r\nb\r\n", &["a", "b"], "dos", true);
+        check("a\rb\r", &["a", "b"], "mac", true);
+        check("a\rb", &["a", "b"], "mac", false);
+    }
+
+    #[test]
+    fn mixed_line_endings_keep_stray_crs() {
+        check("a\r\nb\nc\r\n", &["a\r", "b", "c\r"], "unix", true);
+        check("a\rb\nc", &["a\rb", "c"], "unix", false);
+    }
+
+    #[test]
+    fn final_newline() {
+        check("", &[""], "unix", false);
+        check("\n", &[""], "unix", true);
+        check("a", &["a"], "unix", false);
+        check("a\n\n", &["a", ""], "unix", true);
+    }
+
+    #[test]
+    fn round_trips() {
+        for text in [
+            "",
+            "\n",
+            "\r",
+            "\r\n",
+            "a",
+            "a\n",
+            "a\r\nb\r\n",
+            "a\rb\r",
+            "a\r\nb\nc\r\n",
+            "a\rb\nc",
+        ] {
+            round_trip(text);
+        }
+    }
+
+    // A fresh directory for a test to work in
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vir-test-{}-{}", process::id(), name));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, data: &str, force: bool) -> Result<()> {
+        let options = WriteOptions {
+            fsync: false,
+            backup: None,
+            mkdir: false,
+            force,
+            private: false,
+        };
+        write_file(path.to_str().unwrap(), data.as_bytes(), &options)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions_and_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("keeps");
+        let path = dir.join("file");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        // Only works as root, which can give the file away to check it's given back
+        std::os::unix::fs::chown(&path, Some(1234), Some(1234)).unwrap_or_default();
+        let before = fs::metadata(&path).unwrap();
+
+        write(&path, "new", false).unwrap();
+        let after = fs::metadata(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(after.mode() & 0o777, 0o640);
+        assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+        assert_ne!(after.ino(), before.ino());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_write_leaves_the_original() {
+        let dir = temp_dir("failed");
+        let path = dir.join("file");
+        fs::write(&path, "old").unwrap();
+        // Something in the way of the temporary file
+        fs::create_dir(temp_path(&path)).unwrap();
+
+        assert!(write(&path, "new", true).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_symlinks() {
+        let dir = temp_dir("symlink");
+        let path = dir.join("file");
+        let link = dir.join("link");
+        fs::write(&path, "old").unwrap();
+        std::os::unix::fs::symlink("file", &link).unwrap();
+
+        write(&link, "new", false).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_are_only_written_in_place_with_force() {
+        let dir = temp_dir("hardlink");
+        let path = dir.join("file");
+        let link = dir.join("link");
+        fs::write(&path, "old").unwrap();
+        fs::hard_link(&path, &link).unwrap();
+
+        let e = write(&path, "new", false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs::read_to_string(&link).unwrap(), "old");
+
+        write(&path, "new", true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&link).unwrap(), "new");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stamps() {
+        let dir = temp_dir("stamp");
+        let path = dir.join("file");
+        let name = path.to_str().unwrap();
+        fs::write(&path, "abc").unwrap();
+        let mut stamp = FileStamp::new(name, b"abc");
+        assert!(!stamp.check(name));
+
+        // Touched without changing the contents
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(!stamp.check(name));
+        assert_eq!(stamp.modified, Some(later));
+
+        // The same length with different contents
+        fs::write(&path, "abd").unwrap();
+        assert!(stamp.check(name));
+
+        // A deleted file doesn't count as changed
+        let mut stamp = FileStamp::new(name, b"abd");
+        fs::remove_file(&path).unwrap();
+        assert!(!stamp.check(name));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
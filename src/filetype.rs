@@ -0,0 +1,156 @@
+use std::path::Path;
+
+use crate::config::Config;
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("toml", "toml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("md", "markdown"),
+    ("markdown", "markdown"),
+    ("sh", "sh"),
+    ("bash", "sh"),
+    ("zsh", "sh"),
+    ("mk", "make"),
+    ("py", "python"),
+    ("c", "c"),
+    ("h", "c"),
+    ("json", "json"),
+    ("nix", "nix"),
+];
+
+const FILENAMES: &[(&str, &str)] = &[
+    ("Makefile", "make"),
+    ("makefile", "make"),
+    ("GNUmakefile", "make"),
+    ("Cargo.lock", "toml"),
+    (".bashrc", "sh"),
+    (".zshrc", "sh"),
+    (".profile", "sh"),
+];
+
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("sh", "sh"),
+    ("bash", "sh"),
+    ("zsh", "sh"),
+    ("dash", "sh"),
+    ("python", "python"),
+    ("python3", "python"),
+    ("make", "make"),
+];
+
+/// Options applied to every buffer of a filetype, before any `[filetype.<name>]` config
+const DEFAULTS: &[(&str, &[(&str, &str)])] = &[
+    ("rust", &[("commentstring", "// %s")]),
+    ("c", &[("commentstring", "/* %s */")]),
+    ("json", &[("tabstop", "2")]),
+    ("yaml", &[("tabstop", "2"), ("expandtab", "true")]),
+    ("nix", &[("tabstop", "2"), ("commentstring", "# %s")]),
+    ("markdown", &[("commentstring", "<!-- %s -->")]),
+    ("make", &[("expandtab", "false"), ("commentstring", "# %s")]),
+    ("toml", &[("commentstring", "# %s")]),
+    ("sh", &[("commentstring", "# %s")]),
+    ("python", &[("commentstring", "# %s")]),
+];
+
+// Modelines are only looked for this close to the start or end of the file, like vim
+const MODELINE_LINES: usize = 5;
+
+/// Work out the filetype of a file from its name, falling back to its shebang line. A
+/// `filetype`/`ft` in a modeline takes priority but is applied along with the other modeline
+/// options, see [`modeline`]
+pub(crate) fn detect(path: &str, lines: &[String], config: &Config) -> Option<String> {
+    let path = Path::new(path);
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        if let Some(ft) = config.filetype_for_extension(ext) {
+            return Some(ft.to_string());
+        }
+
+        if let Some((_, ft)) = EXTENSIONS.iter().find(|(e, _)| *e == ext) {
+            return Some(ft.to_string());
+        }
+    }
+
+    if let Some((_, ft)) = FILENAMES.iter().find(|(n, _)| *n == file_name) {
+        return Some(ft.to_string());
+    }
+
+    lines.first().and_then(|l| shebang(l)).map(String::from)
+}
+
+fn shebang(line: &str) -> Option<&'static str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+
+    // `#!/usr/bin/env [-S] bash`
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-'))?;
+    }
+
+    INTERPRETERS
+        .iter()
+        .find(|(name, _)| *name == program)
+        .map(|(_, ft)| *ft)
+}
+
+/// Built-in option values for a filetype, as (name, value) pairs
+pub(crate) fn defaults(filetype: &str) -> &'static [(&'static str, &'static str)] {
+    DEFAULTS
+        .iter()
+        .find(|(ft, _)| *ft == filetype)
+        .map_or(&[], |(_, opts)| opts)
+}
+
+/// Find a modeline near the start or end of the file and return its option arguments, in
+/// the same form `:set` accepts. Supports both `vim: ts=2 et:` and `vim: set ts=2 et :`, and
+/// also accepts `vi:` and `vir:` as the leader
+pub(crate) fn modeline(lines: &[String]) -> Option<Vec<String>> {
+    let tail = lines
+        .len()
+        .saturating_sub(MODELINE_LINES)
+        .max(MODELINE_LINES);
+
+    lines
+        .iter()
+        .take(MODELINE_LINES)
+        .chain(lines.iter().skip(tail))
+        .find_map(|l| parse_modeline(l))
+}
+
+fn parse_modeline(line: &str) -> Option<Vec<String>> {
+    let rest = ["vir:", "vim:", "vi:", "ex:"].iter().find_map(|leader| {
+        let i = line.find(leader)?;
+        // The leader has to start the line or follow whitespace
+        if i == 0 || line[..i].ends_with(char::is_whitespace) {
+            Some(&line[i + leader.len()..])
+        } else {
+            None
+        }
+    })?;
+    let rest = rest.trim_start();
+
+    let args = match rest
+        .strip_prefix("set ")
+        .or_else(|| rest.strip_prefix("se "))
+    {
+        // In the `set` form everything after the closing ':' is ignored
+        Some(set) => set
+            .split(':')
+            .next()?
+            .split_whitespace()
+            .collect::<Vec<_>>(),
+        None => rest
+            .split(|c: char| c == ':' || c.is_whitespace())
+            .filter(|a| !a.is_empty())
+            .collect(),
+    };
+
+    if args.is_empty() {
+        None
+    } else {
+        Some(args.into_iter().map(String::from).collect())
+    }
+}
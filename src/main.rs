@@ -1,31 +1,86 @@
-use std::env::args;
-use std::fs::File;
-use std::io::{stdout, BufRead, BufReader, Result, Write};
+use std::env;
+use std::io::{stdout, Result, Write};
+use std::process;
 
 use actions::EditorAction;
+use cli::{Args, Command};
 use config::Config;
-use crossterm::event::{self, read};
-use crossterm::terminal::{self, disable_raw_mode};
-use crossterm::{cursor, queue};
+use crossterm::event::{self, poll, read};
+use crossterm::terminal::disable_raw_mode;
 use editor::EditorState;
+use fileio::FileData;
 use modes::process_key_event;
+use options::{OptionSpec, OptionValue};
 use term::Term;
 
 mod actions;
+mod buffer;
+mod cli;
+mod commands;
 mod editor;
+mod encoding;
+mod fileio;
+mod filetype;
+mod message;
+mod options;
+mod statusline;
+mod swap;
+mod syntax;
 mod term;
+mod theme;
+mod window;
+mod wrap;
 
 mod config;
 mod modes;
 
-fn main_loop(filename: &str, data: Vec<String>) -> Result<()> {
+/// Run the editor until it quits. Returns false if it failed in pipe mode, having quit
+/// without writing anything
+fn main_loop(args: Args, files: Vec<(String, Option<FileData>)>, config: Config) -> Result<bool> {
     let term = Term::new()?;
-    let config = Config::init()?;
-    let mut editor = EditorState::new(data, term, config);
+    let mut editor = EditorState::new(files, term, config);
 
-    editor.redraw()?;
+    // Each file given has its own buffer, in the same order
+    if args.pipe {
+        editor.set_output(0);
+    }
+    for (index, file) in args.files.iter().enumerate() {
+        if let Some((line, col)) = file.position {
+            editor.place_cursor(index, line, col);
+        }
+        // Text from stdin hasn't been saved anywhere, unless it is going back out in pipe mode
+        if file.name == "-" && !args.pipe {
+            editor.set_unsaved(index);
+        }
+    }
+
+    // Commands from -c run as if typed, and can quit straight away
+    let mut quit = None;
+    for command in &args.commands {
+        let action = commands::execute(command, &mut editor)?;
+        quit = handle_action(&mut editor, action);
+        if quit.is_some() {
+            break;
+        }
+    }
+
+    while quit.is_none() {
+        editor.redraw()?;
+
+        // Wake up to redraw when a message expires, or to write swap files and look for
+        // changed files once idle
+        let timeout = [editor.message_timeout(), editor.idle_timeout()]
+            .into_iter()
+            .flatten()
+            .min();
+        if let Some(timeout) = timeout {
+            if !poll(timeout)? {
+                editor.check_files();
+                editor.update_swaps(true);
+                continue;
+            }
+        }
 
-    loop {
         let action = match read()? {
             event::Event::Key(ke) => process_key_event(ke, &mut editor)?,
             event::Event::Resize(w, h) => {
@@ -35,43 +90,92 @@ fn main_loop(filename: &str, data: Vec<String>) -> Result<()> {
             _ => continue,
         };
 
-        match action {
-            EditorAction::None => (),
-            EditorAction::Exit => break,
-            EditorAction::Save => editor.save_file(filename)?,
-        }
-
-        editor.redraw()?;
+        quit = handle_action(&mut editor, action);
+        editor.check_files();
+        editor.update_swaps(false);
     }
 
-    queue!(
-        stdout(),
-        terminal::Clear(terminal::ClearType::All),
-        cursor::MoveTo(0, 0),
-    )?;
+    editor.clear_screen()?;
 
-    stdout().flush()?;
+    if args.pipe {
+        if quit == Some(true) {
+            return Ok(false);
+        }
+        let data = editor.output_data()?;
+        stdout().write_all(&data)?;
+        stdout().flush()?;
+    }
 
-    Ok(())
+    Ok(true)
 }
 
-fn read_file(name: &str) -> Result<Vec<String>> {
-    let f = File::open(name).expect("Couldn't open file for reading");
-    let buf = BufReader::new(f);
-
-    buf.lines().collect()
+// Carry out an action that needs the main loop. Returns Some when quitting, true if forced
+fn handle_action(editor: &mut EditorState, action: EditorAction) -> Option<bool> {
+    match action {
+        EditorAction::None => None,
+        EditorAction::Exit => editor.can_quit().then_some(false),
+        EditorAction::ForceExit => Some(true),
+        EditorAction::Save => {
+            editor.save_file();
+            None
+        }
+    }
 }
 
 fn main() -> Result<()> {
+    let args = match cli::parse(env::args().skip(1)) {
+        Ok(Command::Edit(args)) => args,
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Ok(Command::Version) => {
+            println!("vir {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Err(e) => {
+            eprint!("vir: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    // Stdout may be a pipe, so report panics where they will be seen
     std::panic::set_hook(Box::new(|p| {
         disable_raw_mode().unwrap_or_default();
-        println!("{}", p)
+        eprintln!("{}", p)
     }));
 
-    let filename = &args().collect::<Vec<_>>()[1];
-    let data = read_file(filename)?;
+    let mut config = Config::init(args.clean)?;
+    if args.readonly {
+        let spec = OptionSpec::lookup("readonly").unwrap();
+        config.options_mut().set(spec, OptionValue::Bool(true));
+    }
+    let encodings = config
+        .options()
+        .get("fileencodings")
+        .map_or("", |v| v.as_str())
+        .to_string();
+
+    let mut files = Vec::new();
+    for file in &args.files {
+        let data = match file.name.as_str() {
+            "-" => fileio::read_stdin(&encodings).map(|data| (String::new(), Some(data))),
+            name => fileio::read_file(name, &encodings).map(|data| (name.to_string(), data)),
+        };
+        match data {
+            Ok(data) => files.push(data),
+            Err(e) => {
+                eprintln!("vir: {}: {}", file.name, e);
+                process::exit(1);
+            }
+        }
+    }
 
-    let _a = main_loop(filename, data);
+    let result = main_loop(args, files, config);
+    disable_raw_mode()?;
 
-    disable_raw_mode()
+    if !result? {
+        process::exit(1);
+    }
+    Ok(())
 }
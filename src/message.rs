@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl MessageLevel {
+    /// The theme element messages of this level are drawn with
+    pub fn element(&self) -> &'static str {
+        match self {
+            MessageLevel::Info => "message_info",
+            MessageLevel::Warning => "message_warning",
+            MessageLevel::Error => "message_error",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Expiry {
+    Keypress,
+    After(Duration), // Counted from when the message is first shown
+}
+
+pub(crate) struct Message {
+    pub text: String,
+    pub level: MessageLevel,
+    expiry: Expiry,
+    shown: Option<Instant>,
+}
+
+impl Message {
+    fn deadline(&self) -> Option<Instant> {
+        match (self.expiry, self.shown) {
+            (Expiry::After(d), Some(shown)) => Some(shown + d),
+            _ => None,
+        }
+    }
+}
+
+/// Messages waiting to be shown in the status line. The oldest is shown until the next
+/// keypress, or until its timeout runs out for timed messages
+#[derive(Default)]
+pub(crate) struct MessageQueue {
+    messages: VecDeque<Message>,
+}
+
+impl MessageQueue {
+    /// Queue a message that disappears `timeout` after it is first shown
+    pub fn push_timed(&mut self, level: MessageLevel, text: String, timeout: Duration) {
+        self.push(level, text, Expiry::After(timeout))
+    }
+
+    /// Queue a message that stays until the next key is pressed
+    pub fn push_until_key(&mut self, level: MessageLevel, text: String) {
+        self.push(level, text, Expiry::Keypress)
+    }
+
+    fn push(&mut self, level: MessageLevel, text: String, expiry: Expiry) {
+        self.messages.push_back(Message {
+            text,
+            level,
+            expiry,
+            shown: None,
+        })
+    }
+
+    /// The message to show now, starting its timer if this is the first time it is shown
+    pub fn current(&mut self) -> Option<&Message> {
+        self.expire();
+        let msg = self.messages.front_mut()?;
+        msg.shown.get_or_insert_with(Instant::now);
+        Some(msg)
+    }
+
+    /// Time until the shown message expires, if it is waiting on a timer
+    pub fn time_left(&self) -> Option<Duration> {
+        self.messages
+            .front()?
+            .deadline()
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Drop messages whose timers have run out
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        while self
+            .messages
+            .front()
+            .and_then(|m| m.deadline())
+            .is_some_and(|d| d <= now)
+        {
+            self.messages.pop_front();
+        }
+    }
+
+    /// Any key dismisses the shown message, timed or not
+    pub fn keypress(&mut self) {
+        if self.messages.front().is_some_and(|m| m.shown.is_some()) {
+            self.messages.pop_front();
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::Result;
 
+use command::process_command_input;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use insert::process_insert_input;
 use normal::process_normal_input;
@@ -7,6 +8,7 @@ use visual::process_visual_input;
 
 use crate::{actions::EditorAction, editor::EditorState};
 
+mod command;
 mod insert;
 mod normal;
 mod pending;
@@ -17,6 +19,7 @@ pub enum Mode {
     Insert,
     Normal,
     Visual,
+    Command,
 }
 
 impl Mode {
@@ -25,6 +28,7 @@ impl Mode {
             Mode::Insert => "Insert",
             Mode::Normal => "Normal",
             Mode::Visual => "Visual",
+            Mode::Command => "Command",
         }
     }
 }
@@ -45,13 +49,21 @@ fn is_special(ke: &KeyEvent) -> Option<EditorAction> {
 }
 
 pub fn process_key_event(ke: KeyEvent, buf: &mut EditorState) -> Result<EditorAction> {
+    buf.dismiss_message();
+
+    // Everything typed in one insert is undone together, anything else is its own change
+    if *buf.mode() != Mode::Insert {
+        buf.break_undo();
+    }
+
     if let Some(action) = is_special(&ke) {
-        return Ok(action);
+        Ok(action)
     } else {
         match buf.mode() {
             Mode::Insert => process_insert_input(ke, buf),
             Mode::Normal => process_normal_input(ke, buf),
             Mode::Visual => process_visual_input(ke, buf),
+            Mode::Command => process_command_input(ke, buf),
         }
     }
 }
@@ -0,0 +1,25 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::io::Result;
+
+use crate::{actions::EditorAction, commands, editor::EditorState};
+
+pub fn process_command_input(ke: KeyEvent, state: &mut EditorState) -> Result<EditorAction> {
+    if ke.modifiers != KeyModifiers::NONE && ke.modifiers != KeyModifiers::SHIFT {
+        return Ok(EditorAction::None);
+    }
+
+    match ke.code {
+        KeyCode::Char(c) => state.cmdline_push(c),
+        // Deleting past the start of the line leaves command mode, like vim
+        KeyCode::Backspace if !state.cmdline_pop() => state.normal_mode(),
+        KeyCode::Esc => state.normal_mode(),
+        KeyCode::Enter => {
+            let line = state.take_cmdline();
+            state.normal_mode();
+            return commands::execute(&line, state);
+        }
+        _ => (),
+    }
+
+    Ok(EditorAction::None)
+}
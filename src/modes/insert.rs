@@ -3,7 +3,7 @@ use std::io::Result;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
 use crate::actions::{self, EditorAction, InsertAction};
-use crate::editor::{EditorState, TextObject};
+use crate::editor::EditorState;
 
 pub fn process_insert_input(ke: KeyEvent, state: &mut EditorState) -> Result<EditorAction> {
     let action = parse_insert_input(ke, state)?;
@@ -11,7 +11,7 @@ pub fn process_insert_input(ke: KeyEvent, state: &mut EditorState) -> Result<Edi
 
     match action {
         InsertAction::Write(c) => state.insert_text(cursor_pos, &c.to_string()),
-        InsertAction::DelForw => state.delete(TextObject::Char(cursor_pos)),
+        InsertAction::DelForw => state.delete_forward(cursor_pos),
         InsertAction::DelBack => {
             if cursor_pos.index() == 0 {
                 if cursor_pos.lnum() != 0 {
@@ -36,7 +36,8 @@ pub fn process_insert_input(ke: KeyEvent, state: &mut EditorState) -> Result<Edi
 
 fn parse_insert_input(ke: KeyEvent, state: &mut EditorState) -> Result<InsertAction> {
     Ok(
-        if ke.modifiers == KeyModifiers::from_name("NONE").expect("Unable to check modifiers") {
+        // Shift only changes which character was typed, e.g. L rather than l
+        if (ke.modifiers - KeyModifiers::SHIFT).is_empty() {
             let action = match ke.code {
                 KeyCode::Char(c) => Some(InsertAction::Write(c)),
                 _ => state.insert_bind(&actions::get_key_name(&ke)),
@@ -1,48 +1,184 @@
-use crossterm::event::{KeyEvent, KeyModifiers};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use std::io::Result;
 
 use crate::{
-    actions::{self, EditorAction, NormalAction},
-    editor::{EditorState, TextObject},
+    actions::{self, EditorAction, NormalAction, ViewAction, WindowAction},
+    editor::{EditorState, TextObject, ViewPosition},
+    window::Split,
 };
 
 use super::pending;
 
 pub fn process_normal_input(ke: KeyEvent, state: &mut EditorState) -> Result<EditorAction> {
     let action = parse_normal_input(ke, state)?;
+
+    if let NormalAction::None = action {
+        // Digits that aren't bound to anything build up a count for the next command
+        if let KeyCode::Char(c @ '0'..='9') = ke.code {
+            if c != '0' || state.has_count() {
+                state.push_count(c as usize - '0' as usize);
+                return Ok(EditorAction::None);
+            }
+        }
+    }
+
+    let count = state.take_count();
+
+    match action {
+        NormalAction::Delete
+        | NormalAction::Change
+        | NormalAction::ReplaceChar
+        | NormalAction::SetMark
+        | NormalAction::GotoMark => return process_operator(action, ke, count, state),
+        NormalAction::View => {
+            match pending::await_view(state, ke)? {
+                ViewAction::Center => state.view_cursor_at(ViewPosition::Center),
+                ViewAction::Top => state.view_cursor_at(ViewPosition::Top),
+                ViewAction::Bottom => state.view_cursor_at(ViewPosition::Bottom),
+                ViewAction::ScrollUp => state.scroll_vertical(-(count as isize)),
+                ViewAction::ScrollDown => state.scroll_vertical(count as isize),
+                ViewAction::ScrollLeft => state.scroll_horizontal(-(count as isize)),
+                ViewAction::ScrollRight => state.scroll_horizontal(count as isize),
+                ViewAction::Cancel => (),
+            }
+            return Ok(EditorAction::None);
+        }
+        NormalAction::Window => {
+            let action = pending::await_window(state, ke)?;
+            if let Err(e) = process_window(action, count as isize, state) {
+                state.error(e);
+            }
+            return Ok(EditorAction::None);
+        }
+        _ => (),
+    }
+
+    for _ in 0..count {
+        if let Some(a) = process_normal_action(action, state) {
+            return Ok(a);
+        }
+    }
+
+    Ok(EditorAction::None)
+}
+
+/// Handle commands that wait for more input before acting, applied `count` times
+fn process_operator(
+    action: NormalAction,
+    ke: KeyEvent,
+    count: usize,
+    state: &mut EditorState,
+) -> Result<EditorAction> {
+    match action {
+        NormalAction::ReplaceChar => {
+            let c = pending::await_char(state, ke)?;
+            state.replace_chars(c, count);
+        }
+        NormalAction::SetMark => match pending::await_char(state, ke)? {
+            c if c.is_ascii_alphabetic() => state.set_mark(c),
+            _ => state.error("Invalid mark".to_string()),
+        },
+        NormalAction::GotoMark => {
+            let c = pending::await_char(state, ke)?;
+            if !state.goto_mark(c) {
+                state.error("Mark not set".to_string());
+            }
+        }
+        NormalAction::Delete | NormalAction::Change => {
+            let textobject = pending::await_textobject(state, ke)?;
+            for _ in 0..count {
+                let cursor_pos = state.cursor().pos();
+                state.delete(state.resolve_textobject(textobject, cursor_pos));
+            }
+
+            if let NormalAction::Change = action {
+                state.insert_mode()
+            }
+        }
+        _ => (),
+    }
+
+    Ok(EditorAction::None)
+}
+
+/// Handle the second key of a window or tab command
+fn process_window(
+    action: WindowAction,
+    count: isize,
+    state: &mut EditorState,
+) -> std::result::Result<(), String> {
+    match action {
+        WindowAction::Split => return state.split_window(Split::Horizontal, None),
+        WindowAction::VSplit => return state.split_window(Split::Vertical, None),
+        WindowAction::Close => return state.close_window(state.current_window()),
+        WindowAction::Only => state.only_window(),
+        WindowAction::Next => state.cycle_window(count),
+        WindowAction::Up => state.focus_neighbour(Split::Horizontal, false),
+        WindowAction::Down => state.focus_neighbour(Split::Horizontal, true),
+        WindowAction::Left => state.focus_neighbour(Split::Vertical, false),
+        WindowAction::Right => state.focus_neighbour(Split::Vertical, true),
+        WindowAction::Grow => state.resize_window(Split::Horizontal, count),
+        WindowAction::Shrink => state.resize_window(Split::Horizontal, -count),
+        WindowAction::Widen => state.resize_window(Split::Vertical, count),
+        WindowAction::Narrow => state.resize_window(Split::Vertical, -count),
+        WindowAction::Equalize => state.equalize_windows(),
+        WindowAction::NewTab => return state.new_tab(None),
+        WindowAction::CloseTab => return state.close_tab(state.current_tab()),
+        WindowAction::NextTab => state.cycle_tab(count),
+        WindowAction::PrevTab => state.cycle_tab(-count),
+        WindowAction::Cancel => (),
+    }
+
+    Ok(())
+}
+
+/// Run a single normal mode action, returning an action for the main loop if there is one
+fn process_normal_action(action: NormalAction, state: &mut EditorState) -> Option<EditorAction> {
     let cursor_pos = state.cursor().pos();
 
     match action {
-        NormalAction::ReplaceChar => state.replace(
-            TextObject::Char(cursor_pos),
-            &pending::await_char()?.to_string(),
-        ),
-        NormalAction::Delete => state.delete(pending::await_textobject(state, ke)?),
+        NormalAction::ReplaceChar
+        | NormalAction::Delete
+        | NormalAction::Change
+        | NormalAction::SetMark
+        | NormalAction::GotoMark
+        | NormalAction::View
+        | NormalAction::Window => (),
         NormalAction::DeleteChar => state.delete(TextObject::Char(cursor_pos)),
-        NormalAction::Change => {
-            state.delete(pending::await_textobject(state, ke)?);
-            state.insert_mode()
-        }
         NormalAction::Yank => todo!(),
+        NormalAction::Undo => {
+            if !state.undo() {
+                state.info("Already at oldest change".to_string());
+            }
+        }
+        NormalAction::Redo => {
+            if !state.redo() {
+                state.info("Already at newest change".to_string());
+            }
+        }
         NormalAction::SearchMode => todo!(),
         NormalAction::InsertMode => state.insert_mode(),
         NormalAction::VisualMode => state.visual_mode(),
-        NormalAction::Exit => return Ok(EditorAction::Exit),
-        NormalAction::None => return Ok(EditorAction::None),
+        NormalAction::CommandMode => state.command_mode(),
+        NormalAction::Exit => return Some(EditorAction::Exit),
+        NormalAction::ForceExit => return Some(EditorAction::ForceExit),
+        NormalAction::None => (),
         NormalAction::Up => state.cursor_up(),
         NormalAction::Down => state.cursor_down(),
         NormalAction::Left => state.cursor_left(),
         NormalAction::Right => state.cursor_right(),
         NormalAction::LineStart => state.line_start(),
         NormalAction::LineEnd => state.line_end(),
+        NormalAction::MatchPair => state.jump_to_pair(),
     }
 
-    Ok(EditorAction::None)
+    None
 }
 
 fn parse_normal_input(ke: KeyEvent, state: &EditorState) -> Result<NormalAction> {
     Ok(
-        if ke.modifiers == KeyModifiers::from_name("NONE").expect("Unable to check modifiers") {
+        // Shift only changes which character was typed, e.g. L rather than l
+        if (ke.modifiers - KeyModifiers::SHIFT).is_empty() {
             let action = state.normal_bind(&actions::get_key_name(&ke));
 
             match action {
@@ -3,50 +3,83 @@ use std::io::Result;
 use crossterm::event::{self, Event, KeyCode, KeyEvent};
 
 use crate::{
-    actions,
+    actions::{self, ViewAction, WindowAction},
     editor::{EditorState, TextObject},
 };
-pub(crate) fn await_char() -> Result<char> {
+
+/// Show `keys` as pending in the status line while waiting for the next key
+fn await_key(state: &mut EditorState, keys: &str) -> Result<KeyEvent> {
+    state.set_pending(keys);
+    state.redraw()?;
+
     loop {
-        let c = match event::read()? {
-            Event::Key(ke) => match ke.code {
-                KeyCode::Char(c) => c,
-                _ => continue,
-            },
-            Event::Resize(_, _) => panic!(),
+        match event::read()? {
+            Event::Key(ke) => {
+                state.clear_pending();
+                return Ok(ke);
+            }
+            Event::Resize(w, h) => {
+                state.resize(w as usize, h as usize);
+                state.redraw()?;
+            }
             _ => continue,
-        };
+        }
+    }
+}
+
+pub(crate) fn await_char(state: &mut EditorState, prev: KeyEvent) -> Result<char> {
+    let keys = actions::get_key_name(&prev);
 
-        return Ok(c);
+    loop {
+        if let KeyCode::Char(c) = await_key(state, &keys)?.code {
+            return Ok(c);
+        }
     }
 }
 
-pub(crate) fn await_textobject(state: &EditorState, prev: KeyEvent) -> Result<TextObject> {
+/// Wait for the textobject of an operator. The returned textobject is not yet positioned,
+/// see [`EditorState::resolve_textobject`]
+pub(crate) fn await_textobject(state: &mut EditorState, prev: KeyEvent) -> Result<TextObject> {
+    let keys = actions::get_key_name(&prev);
+
     loop {
-        let textobject = match event::read()? {
-            Event::Key(ke) => {
-                if actions::get_key_name(&ke) != actions::get_key_name(&prev) {
-                    Some(TextObject::Line(0))
-                } else {
-                    state.textobject_bind(&actions::get_key_name(&ke))
-                }
-            }
-            Event::Resize(_, _) => panic!(),
-            _ => continue,
+        let ke = await_key(state, &keys)?;
+
+        // Repeating the operator key (e.g. dd) acts on the whole line
+        let textobject = if actions::get_key_name(&ke) == keys {
+            Some(TextObject::Line(0))
+        } else {
+            state.textobject_bind(&actions::get_key_name(&ke))
         };
 
         if let Some(textobject) = textobject {
-            let pos = state.cursor().pos();
-
-            return Ok(match textobject {
-                TextObject::CancelOp => TextObject::None,
-                TextObject::Char(_) => TextObject::Char(pos),
-                TextObject::Line(_) => TextObject::Line(pos.lnum()),
-                TextObject::LineEnd(_, _) => state.get_lineend_textobject(pos),
-                TextObject::Word(_, _) => state.get_word_textobject(pos),
-                TextObject::None => panic!("Shouldn't see None here"),
-                TextObject::Selection(_) => panic!("Shouldn't see Selection here"),
-            });
+            return Ok(textobject);
+        }
+    }
+}
+
+/// Wait for the second key of a view command
+pub(crate) fn await_view(state: &mut EditorState, prev: KeyEvent) -> Result<ViewAction> {
+    let keys = actions::get_key_name(&prev);
+
+    loop {
+        let ke = await_key(state, &keys)?;
+
+        if let Some(action) = state.view_bind(&actions::get_key_name(&ke)) {
+            return Ok(action);
+        }
+    }
+}
+
+/// Wait for the second key of a window command
+pub(crate) fn await_window(state: &mut EditorState, prev: KeyEvent) -> Result<WindowAction> {
+    let keys = actions::get_key_name(&prev);
+
+    loop {
+        let ke = await_key(state, &keys)?;
+
+        if let Some(action) = state.window_bind(&actions::get_key_name(&ke)) {
+            return Ok(action);
         }
     }
 }
@@ -14,7 +14,8 @@ pub fn process_visual_input(ke: KeyEvent, state: &mut EditorState) -> Result<Edi
 
     match action {
         VisualAction::ReplaceChar => {
-            state.replace(TextObject::Char(cursor_pos), &await_char()?.to_string())
+            let c = await_char(state, ke)?.to_string();
+            state.replace(TextObject::Char(cursor_pos), &c)
         }
         VisualAction::Delete => state.delete(TextObject::Selection(cursor_pos)),
         VisualAction::DeleteChar => state.delete(TextObject::Char(cursor_pos)),
@@ -25,6 +26,7 @@ pub fn process_visual_input(ke: KeyEvent, state: &mut EditorState) -> Result<Edi
         VisualAction::Yank => todo!(),
         VisualAction::LineStart => state.line_start(),
         VisualAction::LineEnd => state.line_end(),
+        VisualAction::MatchPair => state.jump_to_pair(),
         VisualAction::Up => state.cursor_up(),
         VisualAction::Down => state.cursor_down(),
         VisualAction::Left => state.cursor_left(),
@@ -39,7 +41,8 @@ pub fn process_visual_input(ke: KeyEvent, state: &mut EditorState) -> Result<Edi
 
 fn parse_visual_input(ke: KeyEvent, state: &mut EditorState) -> Result<VisualAction> {
     Ok(
-        if ke.modifiers == KeyModifiers::from_name("NONE").expect("Unable to check modifiers") {
+        // Shift only changes which character was typed, e.g. L rather than l
+        if (ke.modifiers - KeyModifiers::SHIFT).is_empty() {
             let action = match ke.code {
                 KeyCode::Backspace => state.visual_bind("backspace"),
                 KeyCode::Enter => state.visual_bind("enter"),
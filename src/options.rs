@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use toml::Value;
+
+use crate::encoding::ENCODINGS;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OptionScope {
+    Global,
+    Buffer,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum OptionKind {
+    Bool,
+    Int,
+    Str,
+    Enum(&'static [&'static str]), // Allowed values
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum OptionValue {
+    Bool(bool),
+    Int(i64),
+    Str(String), // Also used for enum options
+}
+
+impl OptionValue {
+    pub fn as_bool(&self) -> bool {
+        matches!(self, OptionValue::Bool(true))
+    }
+
+    pub fn as_int(&self) -> i64 {
+        match self {
+            OptionValue::Int(i) => *i,
+            _ => 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            OptionValue::Str(s) => s,
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Bool(b) => write!(f, "{}", b),
+            OptionValue::Int(i) => write!(f, "{}", i),
+            OptionValue::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+pub(crate) struct OptionSpec {
+    pub name: &'static str,
+    pub short: &'static str,
+    pub kind: OptionKind,
+    pub scope: OptionScope,
+    pub default: &'static str, // Parsed with the same rules as `:set name=value`
+}
+
+const SPECS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "tabstop",
+        short: "ts",
+        kind: OptionKind::Int,
+        scope: OptionScope::Buffer,
+        default: "4",
+    },
+    OptionSpec {
+        name: "expandtab",
+        short: "et",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Buffer,
+        default: "true",
+    },
+    OptionSpec {
+        name: "fileformat",
+        short: "ff",
+        kind: OptionKind::Enum(&["unix", "dos", "mac"]),
+        scope: OptionScope::Buffer,
+        default: "unix",
+    },
+    OptionSpec {
+        name: "endofline",
+        short: "eol",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Buffer,
+        default: "true",
+    },
+    OptionSpec {
+        name: "fileencoding",
+        short: "fenc",
+        kind: OptionKind::Enum(ENCODINGS),
+        scope: OptionScope::Buffer,
+        default: "utf-8",
+    },
+    OptionSpec {
+        name: "bomb",
+        short: "bomb",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Buffer,
+        default: "false",
+    },
+    OptionSpec {
+        name: "fileencodings",
+        short: "fencs",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "ucs-bom,utf-8,latin1",
+    },
+    OptionSpec {
+        name: "readonly",
+        short: "ro",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Buffer,
+        default: "false",
+    },
+    OptionSpec {
+        name: "wordchars",
+        short: "wc",
+        kind: OptionKind::Str,
+        scope: OptionScope::Buffer,
+        default: "*?_-.[]~=&;!#$%^(){}<>",
+    },
+    OptionSpec {
+        name: "filetype",
+        short: "ft",
+        kind: OptionKind::Str,
+        scope: OptionScope::Buffer,
+        default: "",
+    },
+    OptionSpec {
+        name: "commentstring",
+        short: "cms",
+        kind: OptionKind::Str,
+        scope: OptionScope::Buffer,
+        default: "# %s",
+    },
+    OptionSpec {
+        name: "number",
+        short: "nu",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "relativenumber",
+        short: "rnu",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "numberwidth",
+        short: "nuw",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "4",
+    },
+    OptionSpec {
+        name: "wrap",
+        short: "wrap",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "linebreak",
+        short: "lbr",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "showbreak",
+        short: "sbr",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "",
+    },
+    OptionSpec {
+        name: "wrapmotion",
+        short: "wrapmotion",
+        kind: OptionKind::Enum(&["logical", "display"]),
+        scope: OptionScope::Global,
+        default: "logical",
+    },
+    OptionSpec {
+        name: "statusline",
+        short: "stl",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "%M  %f%m%r  %s%=%k   %y  %e  %l:%c  %p%% ",
+    },
+    OptionSpec {
+        name: "messagetime",
+        short: "mt",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "3000",
+    },
+    OptionSpec {
+        name: "scrolloff",
+        short: "so",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "0",
+    },
+    OptionSpec {
+        name: "sidescrolloff",
+        short: "siso",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "0",
+    },
+    OptionSpec {
+        name: "ignorecase",
+        short: "ic",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "syntax",
+        short: "syn",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "true",
+    },
+    OptionSpec {
+        name: "theme",
+        short: "theme",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "default",
+    },
+    OptionSpec {
+        name: "colors",
+        short: "colors",
+        kind: OptionKind::Enum(&["auto", "truecolor", "256", "16"]),
+        scope: OptionScope::Global,
+        default: "auto",
+    },
+    OptionSpec {
+        name: "cursorline",
+        short: "cul",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "list",
+        short: "list",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "listchars",
+        short: "lcs",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "tab:> ,trail:-,nbsp:+",
+    },
+    OptionSpec {
+        name: "matchpairs",
+        short: "mps",
+        kind: OptionKind::Str,
+        scope: OptionScope::Buffer,
+        default: "(:),[:],{:},<:>",
+    },
+    OptionSpec {
+        name: "matchparen",
+        short: "matchparen",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "true",
+    },
+    OptionSpec {
+        name: "fsync",
+        short: "fs",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "true",
+    },
+    OptionSpec {
+        name: "backup",
+        short: "bk",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "backupext",
+        short: "bex",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "~",
+    },
+    OptionSpec {
+        name: "autoread",
+        short: "ar",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "false",
+    },
+    OptionSpec {
+        name: "swapfile",
+        short: "swf",
+        kind: OptionKind::Bool,
+        scope: OptionScope::Global,
+        default: "true",
+    },
+    OptionSpec {
+        name: "directory",
+        short: "dir",
+        kind: OptionKind::Str,
+        scope: OptionScope::Global,
+        default: "",
+    },
+    OptionSpec {
+        name: "updatetime",
+        short: "ut",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "4000",
+    },
+    OptionSpec {
+        name: "updatecount",
+        short: "uc",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "200",
+    },
+    OptionSpec {
+        name: "showtabline",
+        short: "stal",
+        kind: OptionKind::Int,
+        scope: OptionScope::Global,
+        default: "1",
+    },
+];
+
+impl OptionSpec {
+    pub fn lookup(name: &str) -> Option<&'static OptionSpec> {
+        SPECS.iter().find(|s| s.name == name || s.short == name)
+    }
+
+    pub fn all() -> &'static [OptionSpec] {
+        SPECS
+    }
+
+    pub fn parse(&self, value: &str) -> Result<OptionValue, String> {
+        match self.kind {
+            OptionKind::Bool => match value {
+                "true" | "on" | "1" => Ok(OptionValue::Bool(true)),
+                "false" | "off" | "0" => Ok(OptionValue::Bool(false)),
+                _ => Err(format!("Invalid boolean for {}: {}", self.name, value)),
+            },
+            OptionKind::Int => value
+                .parse::<i64>()
+                .map(OptionValue::Int)
+                .map_err(|_| format!("Invalid number for {}: {}", self.name, value)),
+            OptionKind::Str => Ok(OptionValue::Str(value.to_string())),
+            OptionKind::Enum(choices) => {
+                if choices.contains(&value) {
+                    Ok(OptionValue::Str(value.to_string()))
+                } else {
+                    Err(format!(
+                        "Invalid value for {}: {} (expected one of {})",
+                        self.name,
+                        value,
+                        choices.join(", ")
+                    ))
+                }
+            }
+        }
+    }
+
+    pub fn parse_toml(&self, value: &Value) -> Result<OptionValue, String> {
+        match (self.kind, value) {
+            (OptionKind::Bool, Value::Boolean(b)) => Ok(OptionValue::Bool(*b)),
+            (OptionKind::Int, Value::Integer(i)) => Ok(OptionValue::Int(*i)),
+            (_, Value::String(s)) => self.parse(s),
+            _ => Err(format!("Invalid value for {}: {}", self.name, value)),
+        }
+    }
+
+    pub fn default_value(&self) -> OptionValue {
+        self.parse(self.default)
+            .expect("Built-in option default should be valid")
+    }
+}
+
+/// A layer of option values. The global layer holds every option, buffer layers only hold
+/// values that were set locally and fall back to the global layer otherwise.
+pub(crate) struct Options {
+    values: HashMap<&'static str, OptionValue>,
+}
+
+impl Options {
+    pub fn defaults() -> Self {
+        Options {
+            values: SPECS.iter().map(|s| (s.name, s.default_value())).collect(),
+        }
+    }
+
+    pub fn empty() -> Self {
+        Options {
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OptionValue> {
+        self.values.get(OptionSpec::lookup(name)?.name)
+    }
+
+    pub fn set(&mut self, spec: &'static OptionSpec, value: OptionValue) {
+        self.values.insert(spec.name, value);
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &OptionValue)> {
+        self.values.iter().map(|(k, v)| (*k, v))
+    }
+
+    /// Apply every recognised option in a TOML table. Invalid values keep the previous value
+    pub fn apply_table<'a>(&mut self, tab: impl IntoIterator<Item = (&'a String, &'a Value)>) {
+        for (k, v) in tab {
+            if let Some(spec) = OptionSpec::lookup(k) {
+                if let Ok(value) = spec.parse_toml(v) {
+                    self.set(spec, value)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str) -> &'static OptionSpec {
+        OptionSpec::lookup(name).unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        assert_eq!(spec("ts").name, "tabstop");
+        assert_eq!(spec("tabstop").name, "tabstop");
+        assert!(OptionSpec::lookup("tab").is_none());
+
+        for spec in OptionSpec::all() {
+            spec.default_value();
+            assert!(std::ptr::eq(OptionSpec::lookup(spec.short).unwrap(), spec));
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(spec("et").parse("on"), Ok(OptionValue::Bool(true)));
+        assert_eq!(spec("et").parse("0"), Ok(OptionValue::Bool(false)));
+        assert!(spec("et").parse("yes").is_err());
+        assert_eq!(spec("ts").parse("-1"), Ok(OptionValue::Int(-1)));
+        assert!(spec("ts").parse("1.5").is_err());
+        assert_eq!(spec("cms").parse(""), Ok(OptionValue::Str(String::new())));
+        assert_eq!(
+            spec("fenc").parse("latin1"),
+            Ok(OptionValue::Str("latin1".into()))
+        );
+        assert!(spec("fenc").parse("ebcdic").is_err());
+
+        assert_eq!(
+            spec("et").parse_toml(&Value::Boolean(false)),
+            Ok(OptionValue::Bool(false))
+        );
+        assert_eq!(
+            spec("et").parse_toml(&Value::String("on".into())),
+            Ok(OptionValue::Bool(true))
+        );
+        assert_eq!(
+            spec("ts").parse_toml(&Value::Integer(2)),
+            Ok(OptionValue::Int(2))
+        );
+        assert!(spec("ts").parse_toml(&Value::Boolean(true)).is_err());
+        assert!(spec("cms").parse_toml(&Value::Integer(2)).is_err());
+    }
+
+    #[test]
+    fn layers() {
+        let mut options = Options::empty();
+        assert!(options.get("ts").is_none());
+        options.set(spec("ts"), OptionValue::Int(2));
+        assert_eq!(options.get("ts"), Some(&OptionValue::Int(2)));
+        assert_eq!(options.get("tabstop"), Some(&OptionValue::Int(2)));
+        options.unset("tabstop");
+        assert!(options.get("ts").is_none());
+
+        // Invalid and unknown entries are skipped
+        let table = "ts = 3\net = 'maybe'\nnosuch = 1"
+            .parse::<toml::Table>()
+            .unwrap();
+        let mut options = Options::defaults();
+        options.apply_table(&table);
+        assert_eq!(options.get("ts"), Some(&OptionValue::Int(3)));
+        assert_eq!(options.get("et"), Some(&OptionValue::Bool(true)));
+        assert_eq!(options.iter().count(), OptionSpec::all().len());
+    }
+}
@@ -0,0 +1,165 @@
+use std::iter;
+use std::ops::Range;
+
+/// Values that can be shown in the status line
+pub(crate) struct StatusInfo<'a> {
+    pub mode: &'a str,
+    pub filename: &'a str,
+    pub modified: bool,
+    pub readonly: bool,
+    pub lnum: usize, // 1-based
+    pub col: usize,  // 1-based
+    pub lines: usize,
+    pub filetype: &'a str,
+    pub fileformat: &'a str,
+    pub pending: &'a str,
+    pub message: &'a str,
+}
+
+/// Expand a `statusline` format string and fit it to `width` columns. Supported items:
+///
+/// - `%M` mode, `%f` file name, `%m` `[+]` when modified, `%r` `[RO]` when read-only
+/// - `%l` line, `%c` column, `%L` line count, `%p` percentage through the file
+/// - `%y` filetype, `%e` line endings, `%k` pending keys and count, `%s` messages
+/// - `%=` right-align everything after it, `%%` a literal `%`
+///
+/// Also returns the columns taken by `%s`, so messages can be highlighted
+pub(crate) fn render(
+    format: &str,
+    info: &StatusInfo,
+    width: usize,
+) -> (String, Option<Range<usize>>) {
+    let (text, message, split) = expand(format, info);
+    let chars: Vec<char> = text.chars().collect();
+    let (left, right) = chars.split_at(split.unwrap_or(chars.len()));
+
+    // Not enough room, the right side wins as it usually holds the position
+    let keep = left.len().min(width.saturating_sub(right.len()));
+    let padding = width.saturating_sub(keep + right.len());
+    let text = left[..keep]
+        .iter()
+        .chain(iter::repeat(&' ').take(padding))
+        .chain(right)
+        .collect();
+
+    let shift = |i: usize| i - left.len() + keep + padding;
+    let message = message.map(|r| match r.start < left.len() {
+        true => r.start.min(keep)..r.end.min(keep),
+        false => shift(r.start)..shift(r.end),
+    });
+
+    (text, message)
+}
+
+// Expand the items, also returning where `%=` splits the text, in chars
+fn expand(format: &str, info: &StatusInfo) -> (String, Option<Range<usize>>, Option<usize>) {
+    let mut out = String::new();
+    let mut message = None;
+    let mut split = None;
+    let mut chars = format.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('M') => out.push_str(info.mode),
+            Some('f') => out.push_str(info.filename),
+            Some('m') if info.modified => out.push_str("[+]"),
+            Some('m') => (),
+            Some('r') if info.readonly => out.push_str("[RO]"),
+            Some('r') => (),
+            Some('l') => out.push_str(&info.lnum.to_string()),
+            Some('c') => out.push_str(&info.col.to_string()),
+            Some('L') => out.push_str(&info.lines.to_string()),
+            Some('p') => out.push_str(&(info.lnum * 100 / info.lines.max(1)).to_string()),
+            Some('y') => out.push_str(info.filetype),
+            Some('e') => out.push_str(info.fileformat),
+            Some('k') => out.push_str(info.pending),
+            Some('s') => {
+                let start = out.chars().count();
+                out.push_str(info.message);
+                message = Some(start..out.chars().count());
+            }
+            Some('%') => out.push('%'),
+            Some('=') if split.is_none() => split = Some(out.chars().count()),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+
+    (out, message, split)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(modified: bool) -> StatusInfo<'static> {
+        StatusInfo {
+            mode: "NORMAL",
+            filename: "main.rs",
+            modified,
+            readonly: modified,
+            lnum: 12,
+            col: 3,
+            lines: 48,
+            filetype: "rust",
+            fileformat: "unix",
+            pending: "2d",
+            message: "msg",
+        }
+    }
+
+    #[test]
+    fn items() {
+        let expanded = |format: &str, modified| expand(format, &info(modified)).0;
+        assert_eq!(
+            expanded("%M %f%m%r %l:%c/%L %p%% %y %e %k %s", true),
+            "NORMAL main.rs[+][RO] 12:3/48 25% rust unix 2d msg"
+        );
+        assert_eq!(expanded("%f%m%r", false), "main.rs");
+        assert_eq!(expanded("%x 100%", false), "%x 100%");
+    }
+
+    #[test]
+    fn message_columns() {
+        assert_eq!(
+            expand("ab %s", &info(false)),
+            ("ab msg".to_string(), Some(3..6), None)
+        );
+        assert_eq!(render("%s%=%l", &info(false), 10).1, Some(0..3));
+        assert_eq!(render("%l%=%s!", &info(false), 10).1, Some(6..9));
+    }
+
+    #[test]
+    fn alignment() {
+        assert_eq!(render("%f%=%l,%c", &info(false), 16).0, "main.rs     12,3");
+        assert_eq!(render("%=%l", &info(false), 4).0, "  12");
+        assert_eq!(render("%f", &info(false), 9).0, "main.rs  ");
+        // Only the first splits
+        assert_eq!(render("a%=b%=c", &info(false), 8).0, "a   b%=c");
+    }
+
+    #[test]
+    fn escaped_split() {
+        assert_eq!(render("a%%=b", &info(false), 6).0, "a%=b  ");
+        assert_eq!(render("a%%%=b", &info(false), 6).0, "a%   b");
+    }
+
+    #[test]
+    fn narrow() {
+        assert_eq!(render("%f%=%l,%c", &info(false), 6).0, "ma12,3");
+        assert_eq!(render("%f%=%l,%c", &info(false), 2).0, "12,3");
+        assert_eq!(
+            render("%s%=%l,%c", &info(false), 6),
+            ("ms12,3".to_string(), Some(0..2))
+        );
+        assert_eq!(render("%f", &info(false), 0).0, "");
+    }
+}
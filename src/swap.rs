@@ -0,0 +1,245 @@
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{self, Path, PathBuf};
+use std::process;
+
+use crate::fileio::{self, WriteOptions};
+
+const HEADER: &str = "vir swap file";
+
+/// Where a file's swap file goes: hidden next to it, or in `directory` if set, named after
+/// its full path. Unnamed buffers, whose text is nowhere else, go in `directory` or the
+/// temporary directory, named after this process and the buffer's `id`
+pub(crate) fn path(filename: &str, id: usize, directory: &str) -> Option<PathBuf> {
+    if filename.is_empty() {
+        let dir = match directory {
+            "" => env::temp_dir(),
+            dir => PathBuf::from(dir),
+        };
+        return Some(dir.join(format!("vir-{}-{}.swp", process::id(), id)));
+    }
+
+    let file = Path::new(filename);
+    if directory.is_empty() {
+        let name = file.file_name()?.to_string_lossy();
+        return Some(file.with_file_name(format!(".{}.swp", name)));
+    }
+
+    let full = env::current_dir().ok()?.join(file);
+    let name = full.to_string_lossy().replace(path::MAIN_SEPARATOR, "%");
+    Some(Path::new(directory).join(format!("{}.swp", name)))
+}
+
+/// The unsaved text of a buffer, as found in a swap file
+pub(crate) struct SwapFile {
+    pub pid: u32, // Process of the editor that wrote it
+    pub lines: Vec<String>,
+}
+
+impl SwapFile {
+    pub fn read(path: &Path) -> Result<SwapFile> {
+        let text = fs::read_to_string(path)?;
+        let invalid = || Error::new(ErrorKind::InvalidData, "not a swap file");
+
+        let (header, body) = text.split_once("\n\n").ok_or_else(invalid)?;
+        let mut header = header.lines();
+        if header.next() != Some(HEADER) {
+            return Err(invalid());
+        }
+        let pid = header
+            .find_map(|l| l.strip_prefix("pid: "))
+            .and_then(|pid| pid.parse().ok())
+            .ok_or_else(invalid)?;
+
+        Ok(SwapFile {
+            pid,
+            lines: body.split('\n').map(String::from).collect(),
+        })
+    }
+
+    /// Whether the editor that wrote it may still be running, so the file isn't from a crash
+    pub fn running(&self) -> bool {
+        self.pid != process::id() && process_exists(self.pid)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+// Without a way to ask about other processes, assume it may still be running
+#[cfg(not(target_os = "linux"))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+/// A buffer's swap file, which holds its unsaved text so it can be recovered after a crash
+#[derive(Default)]
+pub(crate) struct Swap {
+    path: Option<PathBuf>,      // The swap file written for the buffer, if any
+    written: usize,             // The buffer's change count when it was written
+    failed: bool,               // Writing it failed, and that has been reported
+    pub found: Option<PathBuf>, // Another swap file found when opening the buffer
+}
+
+impl Swap {
+    /// Whether the buffer has had at least `count` changes since the swap file was written
+    pub fn due(&self, changes: usize, count: usize) -> bool {
+        changes.abs_diff(self.written) >= count.max(1)
+    }
+
+    /// Write the buffer's text to `path`, replacing any swap file it had elsewhere
+    pub fn write(
+        &mut self,
+        path: PathBuf,
+        filename: &str,
+        lines: &[String],
+        changes: usize,
+        fsync: bool,
+    ) -> Result<()> {
+        if self.path.as_ref().is_some_and(|p| *p != path) {
+            self.remove();
+        }
+        self.written = changes;
+
+        let data = format!(
+            "{}\npid: {}\nfile: {}\n\n{}",
+            HEADER,
+            process::id(),
+            filename,
+            lines.join("\n")
+        );
+        let options = WriteOptions {
+            fsync,
+            backup: None,
+            mkdir: false,
+            force: false,
+            private: true,
+        };
+
+        match fileio::write_file(&path.to_string_lossy(), data.as_bytes(), &options) {
+            Ok(()) => {
+                self.path = Some(path);
+                self.failed = false;
+                Ok(())
+            }
+            // Only the first failure in a row is reported
+            Err(_) if self.failed => Ok(()),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Take over a swap file that was found, so it is replaced by this buffer's own
+    pub fn adopt(&mut self) {
+        if let Some(path) = self.found.take() {
+            self.remove();
+            self.path = Some(path);
+            self.written = usize::MAX;
+        }
+    }
+
+    pub fn remove(&mut self) {
+        if let Some(path) = self.path.take() {
+            fs::remove_file(path).unwrap_or_default();
+        }
+        self.written = 0;
+    }
+}
+
+impl Drop for Swap {
+    // Closing a buffer or quitting removes its swap file, but a panic leaves it to recover
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileio::tests::temp_dir;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split('\n').map(String::from).collect()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(path("a/b.txt", 1, ""), Some(PathBuf::from("a/.b.txt.swp")));
+        let unnamed = format!("vir-{}-3.swp", process::id());
+        assert_eq!(path("", 3, "/swap"), Some(Path::new("/swap").join(unnamed)));
+
+        let named = path("b.txt", 1, "/swap").unwrap();
+        assert_eq!(named.parent(), Some(Path::new("/swap")));
+        assert!(named.to_string_lossy().ends_with("%b.txt.swp"));
+    }
+
+    #[test]
+    fn write_recover_delete() {
+        let dir = temp_dir("swap");
+        let file = dir.join("file.txt");
+        let path = path(file.to_str().unwrap(), 1, "").unwrap();
+
+        let mut swap = Swap::default();
+        swap.write(path.clone(), "file.txt", &lines("a\n\nb"), 3, false)
+            .unwrap();
+        assert!(!swap.due(4, 2));
+        assert!(swap.due(5, 2));
+
+        // What opening the file again finds
+        let found = SwapFile::read(&path).unwrap();
+        assert_eq!(found.pid, process::id());
+        assert_eq!(found.lines, lines("a\n\nb"));
+        assert!(!found.running());
+
+        // Recovering takes it over, and closing the buffer deletes it
+        let mut other = Swap::default();
+        other.found = Some(path.clone());
+        other.adopt();
+        assert!(other.due(0, 200));
+        drop(other);
+        assert!(!path.exists());
+
+        fs::write(&path, "not a swap file").unwrap();
+        assert_eq!(
+            SwapFile::read(&path).err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidData)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn moves_when_the_path_changes() {
+        let dir = temp_dir("swap-move");
+        let (first, second) = (dir.join(".a.swp"), dir.join(".b.swp"));
+
+        let mut swap = Swap::default();
+        swap.write(first.clone(), "a", &lines("x"), 1, false)
+            .unwrap();
+        swap.write(second.clone(), "b", &lines("x"), 2, false)
+            .unwrap();
+        assert!(!first.exists());
+        assert!(second.exists());
+
+        swap.remove();
+        assert!(!second.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn other_processes() {
+        let swap = |pid| SwapFile {
+            pid,
+            lines: Vec::new(),
+        };
+        assert!(swap(1).running());
+        assert!(!swap(u32::MAX).running());
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+use regex::Regex;
+use toml::{Table, Value};
+
+const BUILTIN: &[&str] = &[
+    include_str!("syntax/rust.toml"),
+    include_str!("syntax/toml.toml"),
+    include_str!("syntax/markdown.toml"),
+    include_str!("syntax/sh.toml"),
+];
+
+// Stops a grammar whose patterns match empty strings from looping forever on a line
+const MAX_STEPS_PER_BYTE: usize = 4;
+
+pub(crate) type ScopeId = usize;
+
+struct Pattern {
+    regex: Regex,
+    scope: Option<ScopeId>,
+    captures: Vec<(usize, ScopeId)>, // Capture group -> scope
+    push: Option<usize>,             // Context to enter after the match
+    pop: bool,                       // Leave the current context after the match
+}
+
+struct Context {
+    scope: Option<ScopeId>, // Applies to all text in the context
+    patterns: Vec<Pattern>,
+}
+
+/// A set of regex rules for tokenizing one language, loaded from a TOML file. Text is matched
+/// against the patterns of the context on top of a stack, starting with `main`, and the
+/// earliest match wins with ties going to the first pattern listed:
+///
+/// ```toml
+/// name = "rust"
+/// filetypes = ["rust"]   # Defaults to [name]
+///
+/// [contexts.main]
+/// patterns = [
+///     { match = '//.*', scope = "comment" },
+///     { match = '"', scope = "string", push = "string" },
+///     { match = '\b(fn)\s+(\w+)', captures = { 1 = "keyword", 2 = "entity.name.function" } },
+/// ]
+///
+/// [contexts.string]
+/// scope = "string"
+/// patterns = [{ match = '\\.', scope = "constant.character.escape" }, { match = '"', pop = true }]
+/// ```
+pub(crate) struct Grammar {
+    pub name: String,
+    filetypes: Vec<String>,
+    contexts: Vec<Context>,
+    scopes: Vec<String>,
+}
+
+impl Grammar {
+    pub fn parse(source: &str) -> Result<Grammar, String> {
+        let tab = source.parse::<Table>().map_err(|e| match e.span() {
+            // The full error is several lines long, too much for the status line
+            Some(span) => format!(
+                "line {}: {}",
+                source[..span.start].matches('\n').count() + 1,
+                e.message()
+            ),
+            None => e.message().to_string(),
+        })?;
+
+        let name = match tab.get("name") {
+            Some(Value::String(name)) => name.clone(),
+            _ => return Err("Grammar has no name".to_string()),
+        };
+
+        let filetypes = match tab.get("filetypes") {
+            Some(Value::Array(fts)) => fts
+                .iter()
+                .filter_map(|ft| ft.as_str().map(String::from))
+                .collect(),
+            _ => vec![name.clone()],
+        };
+
+        let contexts = match tab.get("contexts") {
+            Some(Value::Table(contexts)) => contexts,
+            _ => return Err(format!("{}: grammar has no contexts", name)),
+        };
+
+        // Context names are resolved to indices, with main always first
+        let mut names = vec!["main"];
+        names.extend(contexts.keys().map(|k| k.as_str()).filter(|k| *k != "main"));
+        if !contexts.contains_key("main") {
+            return Err(format!("{}: grammar has no main context", name));
+        }
+
+        let mut grammar = Grammar {
+            name: name.clone(),
+            filetypes,
+            contexts: Vec::new(),
+            scopes: Vec::new(),
+        };
+
+        for ctx_name in &names {
+            let ctx = grammar
+                .parse_context(&contexts[*ctx_name], &names)
+                .map_err(|e| format!("{}: context {}: {}", name, ctx_name, e))?;
+            grammar.contexts.push(ctx);
+        }
+
+        Ok(grammar)
+    }
+
+    fn parse_context(&mut self, value: &Value, names: &[&str]) -> Result<Context, String> {
+        let scope = match value.get("scope") {
+            Some(Value::String(s)) => Some(self.scope_id(s)),
+            _ => None,
+        };
+
+        let patterns = match value.get("patterns") {
+            Some(Value::Array(patterns)) => patterns
+                .iter()
+                .map(|p| self.parse_pattern(p, names))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => Vec::new(),
+        };
+
+        Ok(Context { scope, patterns })
+    }
+
+    fn parse_pattern(&mut self, value: &Value, names: &[&str]) -> Result<Pattern, String> {
+        let regex = match value.get("match") {
+            Some(Value::String(m)) => Regex::new(m).map_err(|e| e.to_string())?,
+            _ => return Err("pattern has no match".to_string()),
+        };
+
+        let scope = match value.get("scope") {
+            Some(Value::String(s)) => Some(self.scope_id(s)),
+            _ => None,
+        };
+
+        let mut captures = Vec::new();
+        if let Some(Value::Table(caps)) = value.get("captures") {
+            for (group, scope) in caps {
+                let group = group
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid capture group {}", group))?;
+                if let Value::String(scope) = scope {
+                    captures.push((group, self.scope_id(scope)));
+                }
+            }
+        }
+
+        let push = match value.get("push") {
+            Some(Value::String(ctx)) => Some(
+                names
+                    .iter()
+                    .position(|n| n == ctx)
+                    .ok_or_else(|| format!("unknown context {}", ctx))?,
+            ),
+            _ => None,
+        };
+
+        let pop = matches!(value.get("pop"), Some(Value::Boolean(true)));
+
+        Ok(Pattern {
+            regex,
+            scope,
+            captures,
+            push,
+            pop,
+        })
+    }
+
+    fn scope_id(&mut self, scope: &str) -> ScopeId {
+        match self.scopes.iter().position(|s| s == scope) {
+            Some(id) => id,
+            None => {
+                self.scopes.push(scope.to_string());
+                self.scopes.len() - 1
+            }
+        }
+    }
+
+    pub fn scope_name(&self, id: ScopeId) -> &str {
+        &self.scopes[id]
+    }
+
+    pub fn handles(&self, filetype: &str) -> bool {
+        self.filetypes.iter().any(|ft| ft == filetype)
+    }
+
+    /// Split a line into scoped spans, starting in and updating the given state
+    fn tokenize(&self, line: &str, state: &mut State) -> Vec<Span> {
+        let mut spans = Vec::new();
+        let mut pos = 0;
+        let mut steps = 0;
+
+        while pos <= line.len() && steps <= line.len() * MAX_STEPS_PER_BYTE + 16 {
+            steps += 1;
+            let ctx = &self.contexts[*state.0.last().unwrap_or(&0)];
+
+            let best = ctx
+                .patterns
+                .iter()
+                .filter_map(|p| Some((p, p.regex.find_at(line, pos)?)))
+                .min_by_key(|(_, m)| m.start());
+
+            let Some((pattern, m)) = best else {
+                push_span(&mut spans, pos..line.len(), ctx.scope);
+                break;
+            };
+
+            push_span(&mut spans, pos..m.start(), ctx.scope);
+
+            let scope = pattern
+                .scope
+                .or_else(|| pattern.push.and_then(|c| self.contexts[c].scope))
+                .or(ctx.scope);
+
+            // Captures override the match scope for the text they cover
+            let mut at = m.start();
+            let caps = if pattern.captures.is_empty() {
+                None
+            } else {
+                pattern.regex.captures_at(line, pos)
+            };
+            if let Some(caps) = caps {
+                let mut groups = pattern
+                    .captures
+                    .iter()
+                    .filter_map(|(g, s)| Some((caps.get(*g)?.range(), *s)))
+                    .collect::<Vec<_>>();
+                groups.sort_by_key(|(r, _)| r.start);
+
+                for (range, s) in groups {
+                    if range.start >= at {
+                        push_span(&mut spans, at..range.start, scope);
+                        push_span(&mut spans, range.clone(), Some(s));
+                        at = range.end;
+                    }
+                }
+            }
+            push_span(&mut spans, at..m.end(), scope);
+
+            let before = state.clone();
+            if pattern.pop && state.0.len() > 1 {
+                state.0.pop();
+            }
+            if let Some(ctx) = pattern.push {
+                state.0.push(ctx);
+            }
+
+            if m.end() > pos {
+                pos = m.end();
+            } else if *state == before {
+                // An empty match that changes nothing, skip a character so we make progress
+                let next = line[pos..].chars().next().map_or(1, |c| c.len_utf8());
+                push_span(&mut spans, pos..(pos + next).min(line.len()), ctx.scope);
+                pos += next;
+            }
+
+            if pos >= line.len() && m.end() >= line.len() {
+                break;
+            }
+        }
+
+        spans
+    }
+}
+
+fn push_span(spans: &mut Vec<Span>, bytes: Range<usize>, scope: Option<ScopeId>) {
+    if let (Some(scope), false) = (scope, bytes.is_empty()) {
+        spans.push(Span { bytes, scope });
+    }
+}
+
+/// Load the built-in grammars, then any `*.toml` grammars in `dir`, which replace built-in
+/// grammars of the same name. Returns errors for grammars that couldn't be loaded
+pub(crate) fn load_grammars(dir: Option<&Path>) -> (Vec<Rc<Grammar>>, Vec<String>) {
+    let mut grammars = Vec::new();
+    let mut errors = Vec::new();
+
+    for source in BUILTIN {
+        match Grammar::parse(source) {
+            Ok(g) => grammars.push(Rc::new(g)),
+            Err(e) => errors.push(format!("Built-in grammar {}", e)),
+        }
+    }
+
+    let entries = dir.and_then(|d| fs::read_dir(d).ok());
+    for entry in entries.into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+
+        match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| Grammar::parse(&s))
+        {
+            Ok(g) => {
+                grammars.retain(|other| other.name != g.name);
+                grammars.push(Rc::new(g));
+            }
+            Err(e) => errors.push(format!("Unable to load {}: {}", path.display(), e)),
+        }
+    }
+
+    (grammars, errors)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct State(Vec<usize>); // Stack of contexts
+
+impl State {
+    fn start() -> Self {
+        State(vec![0])
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Span {
+    pub bytes: Range<usize>,
+    pub scope: ScopeId,
+}
+
+struct HighlightedLine {
+    hash: u64,
+    start: State,
+    end: State,
+    spans: Vec<Span>,
+}
+
+/// Tokenizes a buffer with one grammar, caching each line along with the state it started
+/// in. A line is only tokenized again if its text or starting state changed, so an edit
+/// only costs the lines after it up to where the state settles
+pub(crate) struct Highlighter {
+    grammar: Rc<Grammar>,
+    lines: Vec<Option<HighlightedLine>>,
+    valid: usize, // Lines before this are cached and up to date, later ones are checked again
+}
+
+impl Highlighter {
+    pub fn new(grammar: Rc<Grammar>) -> Self {
+        Highlighter {
+            grammar,
+            lines: Vec::new(),
+            valid: 0,
+        }
+    }
+
+    pub fn grammar(&self) -> Rc<Grammar> {
+        self.grammar.clone()
+    }
+
+    /// Keep the cache lined up with the buffer after lines are inserted at `lnum`
+    pub fn lines_inserted(&mut self, lnum: usize, count: usize) {
+        self.valid = self.valid.min(lnum);
+        if lnum <= self.lines.len() {
+            self.lines
+                .splice(lnum..lnum, (0..count).map(|_| None))
+                .for_each(drop);
+        }
+    }
+
+    /// Keep the cache lined up with the buffer after lines are removed at `lnum`
+    pub fn lines_removed(&mut self, lnum: usize, count: usize) {
+        self.valid = self.valid.min(lnum);
+        let end = (lnum + count).min(self.lines.len());
+        if lnum < end {
+            self.lines.drain(lnum..end);
+        }
+    }
+
+    /// Bring the cache up to date for lines before the end of `range` and return its spans.
+    /// Only lines from the first one changed since the last call are looked at again
+    pub fn spans(&mut self, data: &[String], range: Range<usize>) -> Vec<&[Span]> {
+        let upto = range.end.min(data.len());
+        self.lines.truncate(data.len());
+        self.lines.resize_with(self.lines.len().max(upto), || None);
+        self.valid = self.valid.min(data.len());
+
+        let first = self.valid;
+        let mut state = match first
+            .checked_sub(1)
+            .and_then(|l| self.lines.get(l)?.as_ref())
+        {
+            Some(prev) => prev.end.clone(),
+            None => State::start(),
+        };
+        for (lnum, line) in data.iter().enumerate().take(upto).skip(first) {
+            let hash = hash_line(line);
+
+            match &self.lines[lnum] {
+                Some(cached) if cached.hash == hash && cached.start == state => {
+                    state = cached.end.clone();
+                }
+                _ => {
+                    let start = state.clone();
+                    let spans = self.grammar.tokenize(line, &mut state);
+                    self.lines[lnum] = Some(HighlightedLine {
+                        hash,
+                        start,
+                        end: state.clone(),
+                        spans,
+                    });
+                }
+            }
+        }
+        self.valid = self.valid.max(upto);
+
+        self.lines[range.start.min(upto)..upto]
+            .iter()
+            .map(|l| l.as_ref().map_or(&[][..], |l| &l.spans[..]))
+            .collect()
+    }
+}
+
+fn hash_line(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = r#"
+name = "test"
+
+[contexts.main]
+patterns = [{ match = '/\*', push = "comment" }, { match = '\d+', scope = "number" }]
+
+[contexts.comment]
+scope = "comment"
+patterns = [{ match = '\*/', pop = true }]
+"#;
+
+    // Each line's spans as `text:scope`
+    fn highlight(highlighter: &mut Highlighter, data: &[String]) -> Vec<Vec<String>> {
+        let grammar = highlighter.grammar();
+        highlighter
+            .spans(data, 0..data.len())
+            .iter()
+            .zip(data)
+            .map(|(spans, line)| {
+                spans
+                    .iter()
+                    .map(|s| format!("{}:{}", &line[s.bytes.clone()], grammar.scope_name(s.scope)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_only_highlight_from_the_changed_line() {
+        let grammar = Rc::new(Grammar::parse(GRAMMAR).unwrap());
+        let mut highlighter = Highlighter::new(grammar);
+        let mut data = ["1", "/* 2", "3", "*/ 4"].map(String::from).to_vec();
+        assert_eq!(
+            highlight(&mut highlighter, &data),
+            [
+                vec!["1:number"],
+                vec!["/*:comment", " 2:comment"],
+                vec!["3:comment"],
+                vec!["*/:comment", "4:number"],
+            ]
+        );
+
+        // Line 0 changes without telling the highlighter, so its stale spans show it wasn't
+        // looked at again. Ending the comment early on line 1 changes the lines after it
+        data[0] = "x".to_string();
+        data[1] = "2".to_string();
+        highlighter.lines_removed(1, 1);
+        highlighter.lines_inserted(1, 1);
+        assert_eq!(
+            highlight(&mut highlighter, &data),
+            [
+                vec!["x:number"],
+                vec!["2:number"],
+                vec!["3:number"],
+                vec!["4:number"],
+            ]
+        );
+
+        // Starting it again on the last line changes only that line
+        data[3] = "/* 4".to_string();
+        highlighter.lines_removed(3, 1);
+        highlighter.lines_inserted(3, 1);
+        assert_eq!(
+            highlight(&mut highlighter, &data)[3],
+            ["/*:comment", " 4:comment"]
+        );
+    }
+}
@@ -1,141 +1,649 @@
-use std::io::{stdout, Result, Write};
+use std::fs::OpenOptions;
+use std::io::{stdout, IsTerminal, Result, Write};
+use std::ops::Range;
 
 use crossterm::{
     cursor::{self, SetCursorStyle},
     queue,
-    style::Stylize,
-    terminal::{self, enable_raw_mode},
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, enable_raw_mode, BeginSynchronizedUpdate, EndSynchronizedUpdate},
 };
+use unicode_width::UnicodeWidthChar;
 
-use crate::modes::Mode;
+use crate::{
+    editor::Position,
+    modes::Mode,
+    theme::Theme,
+    window::{Rect, Separator, Split},
+    wrap::{Row, Wrap},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    /// This style drawn on top of `base`, keeping the colors this one doesn't set
+    pub fn over(self, base: Style) -> Style {
+        Style {
+            fg: self.fg.or(base.fg),
+            bg: self.bg.or(base.bg),
+            bold: self.bold || base.bold,
+            italic: self.italic || base.italic,
+            underline: self.underline || base.underline,
+        }
+    }
+}
+
+/// One column of the screen. A wide character takes two, the second holding `CONTINUATION`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cell {
+    pub ch: char,
+    pub style: Style,
+}
+
+const CONTINUATION: char = '\0';
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            ch: ' ',
+            style: Style::default(),
+        }
+    }
+}
+
+/// The full contents of the screen for one redraw
+pub(crate) struct Frame {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Self {
+        Frame {
+            width,
+            height,
+            cells: vec![Cell::default(); width * height],
+        }
+    }
+
+    /// Write a character at (x, y), taking the next column too if it is wide. A wide
+    /// character that doesn't fit before the end of the row is drawn as a space
+    pub fn put(&mut self, x: usize, y: usize, ch: char, style: Style) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let wide = char_width(ch) > 1;
+        let ch = if wide && x + 1 == self.width { ' ' } else { ch };
+        let end = if wide { x + 2 } else { x + 1 }.min(self.width);
+
+        // Don't leave half of a wide character that was partly overwritten
+        let row = y * self.width;
+        if x > 0 && self.cells[row + x].ch == CONTINUATION {
+            self.cells[row + x - 1].ch = ' ';
+        }
+        if end < self.width && self.cells[row + end].ch == CONTINUATION {
+            self.cells[row + end].ch = ' ';
+        }
+
+        self.cells[row + x] = Cell { ch, style };
+        if end > x + 1 {
+            self.cells[row + x + 1] = Cell {
+                ch: CONTINUATION,
+                style,
+            };
+        }
+    }
+
+    /// Write a string starting at (x, y), clipped to the end of the row. Returns the column
+    /// after the last character written
+    pub fn put_str(&mut self, x: usize, y: usize, text: &str, style: Style) -> usize {
+        let mut x = x;
+        for ch in text.chars() {
+            if x >= self.width {
+                break;
+            }
+            self.put(x, y, ch, style);
+            x = (x + char_width(ch)).min(self.width);
+        }
+        x
+    }
+
+    /// Clear a row from column `x` to the end with blanks of the given style
+    pub fn fill_row(&mut self, y: usize, x: usize, style: Style) {
+        for x in x..self.width {
+            self.put(x, y, ' ', style);
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> &Cell {
+        &self.cells[y * self.width + x]
+    }
+
+    /// Copy another frame into this one with its top left corner at (x, y)
+    fn blit(&mut self, other: &Frame, x: usize, y: usize) {
+        for oy in 0..other.height {
+            for ox in 0..other.width {
+                // Wide characters put their own continuations
+                let cell = other.get(ox, oy);
+                if cell.ch != CONTINUATION {
+                    self.put(x + ox, y + oy, cell.ch, cell.style);
+                }
+            }
+        }
+    }
+}
+
+/// Everything needed to draw the editor for one redraw
+pub(crate) struct Screen<'a> {
+    pub tabline: Vec<(String, bool)>, // Label of each tab and whether it is current, if shown
+    pub views: Vec<View<'a>>,
+    pub separators: Vec<Separator>,
+    pub cursor: (usize, usize), // Screen position of the cursor
+    pub mode: &'a Mode,
+    pub status: &'a str,
+    pub status_highlight: Option<(Range<usize>, Style)>, // Columns of the status line
+    pub theme: &'a Theme,
+}
+
+/// Everything needed to draw one window
+pub(crate) struct View<'a> {
+    pub rect: Rect,
+    pub title: Option<String>, // Drawn on the separator below the window, if there is one
+    pub focused: bool,
+    pub lines: &'a [String],
+    pub first_line: usize, // Line number of lines[0]
+    pub x_offset: usize,   // First display column shown
+    pub tabstop: usize,
+    pub wrap: Option<Wrap>,
+    pub gutter: Gutter,
+    pub selection: Option<(Position, Position)>, // Inclusive bounds
+    pub cursorline: bool,                        // Highlight the cursor's line
+    pub pair: Option<(Position, Position)>,      // Matching brackets to highlight
+    pub list: Option<ListChars>,                 // Show whitespace, with the `list` option
+    pub highlights: Vec<Vec<(Range<usize>, Style)>>, // Styled byte ranges for each line
+}
+
+/// How line numbers are shown to the left of the text
+#[derive(Clone, Copy)]
+pub(crate) struct Gutter {
+    pub width: usize, // Including the space after the number, 0 hides the gutter
+    pub relative: bool,
+    pub absolute: bool, // With `relative`, the cursor line shows its absolute number
+    pub cursor_line: usize,
+}
+
+impl Gutter {
+    fn label(&self, lnum: usize) -> String {
+        let n = if !self.relative || (self.absolute && lnum == self.cursor_line) {
+            lnum + 1
+        } else {
+            lnum.abs_diff(self.cursor_line)
+        };
+
+        // Vim left-aligns the cursor line's number in hybrid mode
+        if self.relative && self.absolute && lnum == self.cursor_line {
+            format!("{:<w$} ", n, w = self.width - 1)
+        } else {
+            format!("{:>w$} ", n, w = self.width - 1)
+        }
+    }
+}
+
+/// Glyphs drawn in place of whitespace with the `list` option, parsed from `listchars`
+#[derive(Clone, Default, PartialEq, Debug)]
+pub(crate) struct ListChars {
+    pub tab: Option<(char, char, Option<char>)>, // First column, the rest, and the last column
+    pub trail: Option<char>,                     // Spaces at the end of a line
+    pub nbsp: Option<char>,                      // Non-breaking spaces
+    pub eol: Option<char>,                       // After the end of each line
+}
+
+impl ListChars {
+    /// Parse a comma-separated list like `tab:> ,trail:-,eol:$`, as in vim
+    pub fn parse(value: &str) -> std::result::Result<ListChars, String> {
+        let mut list = ListChars::default();
+
+        for item in value.split(',').filter(|i| !i.is_empty()) {
+            let (name, chars) = item
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid listchars item: {}", item))?;
+            let chars = chars.chars().collect::<Vec<_>>();
+            let single = || match chars[..] {
+                [c] => Ok(c),
+                _ => Err(format!("listchars {} takes one character", name)),
+            };
+
+            match name {
+                "tab" => {
+                    list.tab = match chars[..] {
+                        [a, b] => Some((a, b, None)),
+                        [a, b, c] => Some((a, b, Some(c))),
+                        _ => return Err("listchars tab takes two or three characters".into()),
+                    }
+                }
+                "trail" => list.trail = Some(single()?),
+                "nbsp" => list.nbsp = Some(single()?),
+                "eol" => list.eol = Some(single()?),
+                _ => return Err(format!("Unknown listchars item: {}", name)),
+            }
+        }
+
+        Ok(list)
+    }
+
+    /// The glyph for display column `col` of a tab covering `cols`
+    fn tab_glyph(&self, col: usize, cols: Range<usize>) -> Option<char> {
+        let (first, fill, last) = self.tab?;
+        Some(match last {
+            Some(last) if col + 1 == cols.end => last,
+            _ if col == cols.start => first,
+            _ => fill,
+        })
+    }
+}
+
+/// Byte index of the character covering display column `col`, or the line length if the
+/// line is shorter
+pub(crate) fn index_at_col(line: &str, col: usize, tabstop: usize) -> usize {
+    let mut c = 0;
+    for (i, ch) in line.char_indices() {
+        c = next_col(c, ch, tabstop);
+        if c > col {
+            return i;
+        }
+    }
+    line.len()
+}
+
+/// Display column at which the byte `index` of `line` starts, expanding tabs
+pub(crate) fn display_col(line: &str, index: usize, tabstop: usize) -> usize {
+    let mut col = 0;
+    for (i, ch) in line.char_indices() {
+        if i >= index {
+            break;
+        }
+        col = next_col(col, ch, tabstop);
+    }
+    col
+}
+
+/// Display column after `ch`, when it starts at `col`
+pub(crate) fn next_col(col: usize, ch: char, tabstop: usize) -> usize {
+    if ch == '\t' {
+        (col / tabstop.max(1) + 1) * tabstop.max(1)
+    } else if caret(ch).is_some() {
+        col + 2
+    } else {
+        col + char_width(ch)
+    }
+}
+
+/// Columns a character takes on screen, which is 2 for wide ones like CJK. Zero width
+/// characters are counted as one, as they are drawn on their own
+pub(crate) fn char_width(ch: char) -> usize {
+    ch.width().unwrap_or(1).max(1)
+}
+
+// How a control character other than tab is drawn, as `^M` or, for the C1 controls that
+// Latin-1 text can hold, `~@` to `~_`. Sending it to the terminal would move the cursor
+fn caret(ch: char) -> Option<[char; 2]> {
+    match ch {
+        '\t' => None,
+        '\0'..='\x1f' | '\x7f' => Some(['^', (ch as u8 ^ 0x40) as char]),
+        '\u{80}'..='\u{9f}' => Some(['~', (ch as u8 - 0x40) as char]),
+        _ => None,
+    }
+}
+
+/// Draw a window's text and gutter into a frame the size of the window
+fn draw_view(view: &View, theme: &Theme) -> Frame {
+    let mut frame = Frame::new(view.rect.width, view.rect.height);
+    let normal = theme.ui("normal");
+    let selected = theme.ui("selection").over(normal);
+    let matchparen = theme.ui("matchparen");
+    let whitespace = theme.ui("whitespace");
+    let control = theme.ui("control");
+    let gutter = view.gutter.width;
+
+    for y in 0..frame.height {
+        frame.fill_row(y, 0, normal);
+    }
+
+    let mut y = 0;
+    for (i, line) in view.lines.iter().enumerate() {
+        let lnum = view.first_line + i;
+        let rows = match &view.wrap {
+            Some(wrap) => wrap.rows(line),
+            None => vec![Row {
+                bytes: 0..line.len(),
+                start_col: view.x_offset,
+                continuation: false,
+            }],
+        };
+
+        let highlights = view.highlights.get(i).map_or(&[][..], |h| &h[..]);
+        // Where trailing spaces start, for `listchars` trail
+        let trail = line.trim_end_matches(' ').len();
+
+        let is_cursor_line = lnum == view.gutter.cursor_line;
+        let base = if view.cursorline && is_cursor_line {
+            theme.ui("cursorline").over(normal)
+        } else {
+            normal
+        };
+
+        for row in rows {
+            if y >= frame.height {
+                break;
+            }
+
+            frame.fill_row(y, gutter, base);
+
+            if gutter > 0 && !row.continuation {
+                let style = if is_cursor_line {
+                    theme.ui("gutter_cursor")
+                } else {
+                    theme.ui("gutter")
+                };
+                frame.put_str(0, y, &view.gutter.label(lnum), style.over(normal));
+            }
+
+            let mut x = gutter;
+            if let (Some(wrap), true) = (&view.wrap, row.continuation) {
+                let style = theme.ui("showbreak").over(base);
+                x = frame.put_str(x, y, &wrap.showbreak, style);
+            }
+
+            let width = frame.width.saturating_sub(x);
+            let mut col = display_col(line, row.bytes.start, view.tabstop);
+
+            for (index, ch) in line[row.bytes.clone()].char_indices() {
+                let index = row.bytes.start + index;
+                let next = next_col(col, ch, view.tabstop);
+                let pos = Position::new(lnum, index);
+                let style = match (view.selection, view.pair) {
+                    (Some((start, end)), _) if pos >= start && pos <= end => selected,
+                    (_, Some((a, b))) if pos == a || pos == b => matchparen.over(base),
+                    _ => highlights
+                        .iter()
+                        .find(|(bytes, _)| bytes.contains(&index))
+                        .map_or(base, |(_, style)| style.over(base)),
+                };
+
+                let glyph = |c: usize| {
+                    let list = view.list.as_ref()?;
+                    match ch {
+                        '\t' => list.tab_glyph(c, col..next),
+                        ' ' if index >= trail => list.trail,
+                        '\u{a0}' | '\u{202f}' => list.nbsp,
+                        _ => None,
+                    }
+                };
+
+                for c in col.max(row.start_col)..next.min(row.start_col + width) {
+                    let (ch, style) = match glyph(c) {
+                        Some(g) => (g, whitespace.over(style)),
+                        // Tabs are drawn as spaces up to the next tab stop
+                        None if ch == '\t' => (' ', style),
+                        None => match caret(ch) {
+                            Some(glyph) => (glyph[c - col], control.over(style)),
+                            // Scrolled so only the second half of a wide character shows
+                            None if c > col && c == row.start_col => (' ', style),
+                            // The second half is covered by the first
+                            None if c > col => continue,
+                            None => (ch, style),
+                        },
+                    };
+                    frame.put(x + c - row.start_col, y, ch, style);
+                }
+
+                col = next;
+                if col >= row.start_col + width {
+                    break;
+                }
+            }
+
+            let eol = view.list.as_ref().and_then(|l| l.eol);
+            if let (Some(eol), true) = (eol, row.bytes.end == line.len()) {
+                if col >= row.start_col && col < row.start_col + width {
+                    frame.put(x + col - row.start_col, y, eol, whitespace.over(base));
+                }
+            }
+
+            y += 1;
+        }
+    }
+
+    frame
+}
+
+// Where to draw: stdout, or the controlling terminal when stdout is redirected, as when vir
+// is used in a pipeline
+fn terminal_output() -> Box<dyn Write> {
+    if !stdout().is_terminal() {
+        if let Ok(tty) = OpenOptions::new().write(true).open("/dev/tty") {
+            return Box::new(tty);
+        }
+    }
+    Box::new(stdout())
+}
 
 pub(crate) struct Term {
+    out: Box<dyn Write>, // The terminal, which isn't stdout when that is redirected
     width: usize,
     height: usize,
+    prev: Option<Frame>, // What is currently on screen, None forces a full redraw
 }
 
 impl Term {
     pub fn new() -> Result<Self> {
         enable_raw_mode()?;
-        let mut stdout = stdout();
+        let mut out = terminal_output();
         queue!(
-            stdout,
+            out,
             terminal::Clear(terminal::ClearType::All),
             cursor::MoveTo(0, 0)
         )?;
 
         Ok(Term {
+            out,
             width: terminal::size()?.0 as usize,
             // There was a bug i couldn't fix
             height: terminal::size()?.1 as usize - 1,
+            prev: None,
         })
     }
 
-    pub fn redraw(
-        &self,
-        x_offset: usize,
+    /// A terminal that is never drawn to, for tests
+    #[cfg(test)]
+    pub fn headless(width: usize, height: usize) -> Self {
+        Term {
+            out: Box::new(std::io::sink()),
+            width,
+            height,
+            prev: None,
+        }
+    }
+
+    pub fn redraw(&mut self, screen: &Screen) -> Result<()> {
+        // The extra row is the status line
+        let mut frame = Frame::new(self.width, self.height + 1);
+        let theme = screen.theme;
+
+        for view in &screen.views {
+            let rect = view.rect;
+            frame.blit(&draw_view(view, theme), rect.x, rect.y);
+        }
+
+        if !screen.tabline.is_empty() {
+            frame.fill_row(0, 0, theme.ui("tabline_fill"));
+            let mut x = 0;
+            for (label, current) in &screen.tabline {
+                let style = if *current {
+                    theme.ui("tabline_current")
+                } else {
+                    theme.ui("tabline")
+                };
+                x = frame.put_str(x, 0, label, style);
+            }
+        }
+
+        let separator = theme.ui("separator").over(theme.ui("normal"));
+        for sep in &screen.separators {
+            for i in 0..sep.len {
+                match sep.split {
+                    Split::Horizontal => frame.put(sep.x + i, sep.y, '─', separator),
+                    Split::Vertical => frame.put(sep.x, sep.y + i, '│', separator),
+                }
+            }
+        }
+
+        for view in &screen.views {
+            if let Some(title) = &view.title {
+                let rect = view.rect;
+                let style = if view.focused {
+                    theme.ui("separator_focused").over(separator)
+                } else {
+                    separator
+                };
+                let title = title.chars().take(rect.width.saturating_sub(1));
+                frame.put_str(
+                    rect.x + 1,
+                    rect.y + rect.height,
+                    &title.collect::<String>(),
+                    style,
+                );
+            }
+        }
+
+        let status = theme.ui("statusline");
+        frame.fill_row(self.height, 0, status);
+        frame.put_str(0, self.height, screen.status, status);
+        if let Some((range, style)) = &screen.status_highlight {
+            let mut x = 0;
+            for (i, ch) in screen.status.chars().enumerate() {
+                if range.contains(&i) {
+                    frame.put(x, self.height, ch, style.over(status));
+                }
+                x += char_width(ch);
+            }
+        }
+
+        let cursor_style = match screen.mode {
+            Mode::Insert | Mode::Command => SetCursorStyle::BlinkingBar,
+            Mode::Normal => SetCursorStyle::BlinkingBlock,
+            Mode::Visual => SetCursorStyle::SteadyBlock,
+        };
+
+        self.present(frame, screen.cursor, cursor_style)
+    }
+
+    /// Write the cells that changed since the last frame, in a single synchronized update
+    fn present(
+        &mut self,
+        frame: Frame,
         cursor: (usize, usize),
-        anchor: (usize, usize),
-        mode: &Mode,
-        text: &[String],
+        cursor_style: SetCursorStyle,
     ) -> Result<()> {
-        let mut stdout = stdout();
+        let mut out = Vec::new();
+        queue!(out, BeginSynchronizedUpdate, cursor::Hide)?;
 
-        queue!(
-            stdout,
-            terminal::Clear(terminal::ClearType::All),
-            cursor::MoveTo(0, 0),
-        )?;
+        let prev = match self.prev.take() {
+            Some(prev) if prev.width == frame.width && prev.height == frame.height => prev,
+            _ => {
+                queue!(out, terminal::Clear(terminal::ClearType::All))?;
+                Frame::new(frame.width, frame.height)
+            }
+        };
 
-        for (i, line) in text.iter().enumerate() {
-            let line = if line.len() <= x_offset {
-                ""
-            } else {
-                let limit = self.width.min(line.len() - x_offset);
-                &line[x_offset..x_offset + limit]
-            };
+        // Start from an unknown position and style so the first change sets both
+        let mut pos = None;
+        let mut style = None;
 
-            let (y1, y2) = if cursor.1 > anchor.1 {
-                (anchor.1, cursor.1)
-            } else {
-                (cursor.1, anchor.1)
-            };
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let cell = frame.get(x, y);
+                if cell == prev.get(x, y) || cell.ch == CONTINUATION {
+                    continue;
+                }
+
+                if pos != Some((x, y)) {
+                    queue!(out, cursor::MoveTo(x as u16, y as u16))?;
+                }
 
-            if *mode == Mode::Visual && i >= y1 && i <= y2 && cursor != anchor {
-                if y1 == y2 {
-                    if cursor.0 < anchor.0 {
-                        println!(
-                            "{}{}{}",
-                            &line[..cursor.0 + 1],
-                            &line[cursor.0 + 1..anchor.0 + 1].black().on_white(),
-                            &line[anchor.0 + 1..]
-                        )
-                    } else {
-                        println!(
-                            "{}{}{}",
-                            &line[..anchor.0],
-                            &line[anchor.0..cursor.0].black().on_white(),
-                            &line[cursor.0..]
-                        )
+                if style != Some(cell.style) {
+                    queue!(out, SetAttribute(Attribute::Reset))?;
+                    if let Some(fg) = cell.style.fg {
+                        queue!(out, SetForegroundColor(fg))?;
                     }
-                } else if i == y1 {
-                    if cursor.1 < anchor.1 {
-                        println!(
-                            "{}{}",
-                            &line[..cursor.0 + 1],
-                            &line[cursor.0 + 1..].black().on_white(),
-                        )
-                    } else {
-                        println!(
-                            "{}{}",
-                            &line[..anchor.0],
-                            &line[anchor.0..].black().on_white(),
-                        )
+                    if let Some(bg) = cell.style.bg {
+                        queue!(out, SetBackgroundColor(bg))?;
                     }
-                } else if i == y2 {
-                    if cursor.1 > anchor.1 {
-                        println!(
-                            "{}{}",
-                            &line[..cursor.0].black().on_white(),
-                            &line[cursor.0..],
-                        )
-                    } else {
-                        println!(
-                            "{}{}",
-                            &line[..anchor.0 + 1].black().on_white(),
-                            &line[anchor.0 + 1..],
-                        )
+                    if cell.style.bold {
+                        queue!(out, SetAttribute(Attribute::Bold))?;
                     }
-                } else {
-                    println!("{}", line.black().on_white())
+                    if cell.style.italic {
+                        queue!(out, SetAttribute(Attribute::Italic))?;
+                    }
+                    if cell.style.underline {
+                        queue!(out, SetAttribute(Attribute::Underlined))?;
+                    }
+                    style = Some(cell.style);
                 }
-            } else {
-                println!("{}", line);
-            }
 
-            queue!(stdout, cursor::MoveToColumn(0))?;
+                queue!(out, Print(cell.ch))?;
+                // Terminals may not agree on the width of a zero width character
+                pos = match cell.ch.width() {
+                    Some(0) => None,
+                    _ => Some((x + char_width(cell.ch), y)),
+                };
+            }
         }
 
-        queue!(stdout, cursor::MoveTo(0, self.height as u16))?;
+        queue!(
+            out,
+            SetAttribute(Attribute::Reset),
+            cursor::MoveTo(cursor.0 as u16, cursor.1 as u16),
+            cursor_style,
+            cursor::Show,
+            EndSynchronizedUpdate,
+        )?;
+
+        self.prev = Some(frame);
 
-        print!("{}", mode.get_name());
+        self.out.write_all(&out)?;
+        self.out.flush()
+    }
 
+    /// Clear the screen when quitting
+    pub fn clear(&mut self) -> Result<()> {
         queue!(
-            stdout,
-            cursor::MoveTo(cursor.0 as u16, cursor.1 as u16),
-            match mode {
-                Mode::Insert => SetCursorStyle::BlinkingBar,
-                Mode::Normal => SetCursorStyle::BlinkingBlock,
-                Mode::Visual => SetCursorStyle::SteadyBlock,
-            },
+            self.out,
+            terminal::Clear(terminal::ClearType::All),
+            cursor::MoveTo(0, 0),
         )?;
+        self.out.flush()
+    }
 
-        stdout.flush()
+    /// Forget what is on screen, so the next redraw repaints everything
+    pub fn invalidate(&mut self) {
+        self.prev = None;
     }
 
     pub fn resize(&mut self, width: usize, height: usize) {
         self.width = width;
-        self.height = height;
+        // Leave the last row for the status line, as in `new`
+        self.height = height.saturating_sub(1);
+        self.invalidate();
     }
 
     pub fn width(&self) -> usize {
@@ -146,3 +654,90 @@ impl Term {
         self.height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    // Output that can still be read after the term takes it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn row(frame: &Frame, y: usize) -> String {
+        (0..frame.width).map(|x| frame.get(x, y).ch).collect()
+    }
+
+    #[test]
+    fn widths() {
+        assert_eq!(next_col(0, 'a', 8), 1);
+        assert_eq!(next_col(0, '\u{4e2d}', 8), 2);
+        assert_eq!(next_col(1, '\t', 8), 8);
+        assert_eq!(next_col(0, '\r', 8), 2);
+        assert_eq!(display_col("\u{4e2d}\u{6587}a", 6, 8), 4);
+    }
+
+    #[test]
+    fn wide_characters() {
+        let mut frame = Frame::new(5, 1);
+        assert_eq!(
+            frame.put_str(0, 0, "\u{4e2d}a\u{6587}", Style::default()),
+            5
+        );
+        assert_eq!(row(&frame, 0), "\u{4e2d}\0a\u{6587}\0");
+
+        // No room for the second half at the end of the row
+        let mut frame = Frame::new(3, 1);
+        assert_eq!(
+            frame.put_str(0, 0, "a\u{4e2d}\u{6587}", Style::default()),
+            3
+        );
+        assert_eq!(row(&frame, 0), "a\u{4e2d}\0");
+        assert_eq!(frame.put_str(2, 0, "\u{6587}", Style::default()), 3);
+        assert_eq!(row(&frame, 0), "a  ");
+    }
+
+    #[test]
+    fn overwriting_half_a_wide_character() {
+        let mut frame = Frame::new(4, 1);
+        frame.put_str(0, 0, "\u{4e2d}\u{6587}", Style::default());
+        frame.put(1, 0, 'a', Style::default());
+        assert_eq!(row(&frame, 0), " a\u{6587}\0");
+        frame.put(2, 0, 'b', Style::default());
+        assert_eq!(row(&frame, 0), " ab ");
+    }
+
+    #[test]
+    fn diff_after_wide_characters() {
+        let out = Shared::default();
+        let mut term = Term::headless(4, 1);
+        term.out = Box::new(out.clone());
+        let style = SetCursorStyle::DefaultUserShape;
+
+        let mut frame = Frame::new(4, 1);
+        frame.put_str(0, 0, "\u{4e2d}ab", Style::default());
+        term.present(frame, (0, 0), style).unwrap();
+        let text = String::from_utf8(out.0.take()).unwrap();
+        assert!(text.contains("\u{4e2d}ab"));
+
+        // Only the changed cell is written, after moving to its screen column
+        let mut frame = Frame::new(4, 1);
+        frame.put_str(0, 0, "\u{4e2d}ac", Style::default());
+        term.present(frame, (0, 0), style).unwrap();
+        let text = String::from_utf8(out.0.take()).unwrap();
+        assert!(text.contains("\x1b[1;4H\x1b[0mc"));
+        assert!(!text.contains('a'));
+    }
+}
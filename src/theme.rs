@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crossterm::style::Color;
+use toml::{Table, Value};
+
+use crate::term::Style;
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("default", include_str!("theme/default.toml")),
+    ("midnight", include_str!("theme/midnight.toml")),
+];
+
+// Standard colors in palette order, with the RGB values xterm uses for them
+const ANSI: &[(&str, Color, (u8, u8, u8))] = &[
+    ("black", Color::Black, (0, 0, 0)),
+    ("darkred", Color::DarkRed, (205, 0, 0)),
+    ("darkgreen", Color::DarkGreen, (0, 205, 0)),
+    ("darkyellow", Color::DarkYellow, (205, 205, 0)),
+    ("darkblue", Color::DarkBlue, (0, 0, 238)),
+    ("darkmagenta", Color::DarkMagenta, (205, 0, 205)),
+    ("darkcyan", Color::DarkCyan, (0, 205, 205)),
+    ("grey", Color::Grey, (229, 229, 229)),
+    ("darkgrey", Color::DarkGrey, (127, 127, 127)),
+    ("red", Color::Red, (255, 0, 0)),
+    ("green", Color::Green, (0, 255, 0)),
+    ("yellow", Color::Yellow, (255, 255, 0)),
+    ("blue", Color::Blue, (92, 92, 255)),
+    ("magenta", Color::Magenta, (255, 0, 255)),
+    ("cyan", Color::Cyan, (0, 255, 255)),
+    ("white", Color::White, (255, 255, 255)),
+];
+
+// Levels of each component in the 6x6x6 cube of the 256 color palette
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// How many colors the terminal can show
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ColorMode {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorMode {
+    /// Parse the `colors` option, guessing from the environment for "auto"
+    pub fn from_option(value: &str) -> Self {
+        match value {
+            "truecolor" => ColorMode::TrueColor,
+            "256" => ColorMode::Ansi256,
+            "16" => ColorMode::Ansi16,
+            _ => ColorMode::detect(),
+        }
+    }
+
+    fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorMode::TrueColor
+        } else if term.contains("256color") {
+            ColorMode::Ansi256
+        } else {
+            ColorMode::Ansi16
+        }
+    }
+
+    /// The closest color the terminal can show
+    fn fit(&self, color: Color) -> Color {
+        match (self, color) {
+            (ColorMode::TrueColor, _) => color,
+            (ColorMode::Ansi256, Color::Rgb { r, g, b }) => Color::AnsiValue(nearest_256(r, g, b)),
+            (ColorMode::Ansi16, Color::AnsiValue(n)) if (n as usize) < ANSI.len() => {
+                ANSI[n as usize].1
+            }
+            (ColorMode::Ansi16, Color::AnsiValue(n)) => {
+                let (r, g, b) = rgb_256(n);
+                nearest_16(r, g, b)
+            }
+            (ColorMode::Ansi16, Color::Rgb { r, g, b }) => nearest_16(r, g, b),
+            _ => color,
+        }
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn nearest_16(r: u8, g: u8, b: u8) -> Color {
+    ANSI.iter()
+        .min_by_key(|(_, _, rgb)| distance(*rgb, (r, g, b)))
+        .map_or(Color::Reset, |(_, color, _)| *color)
+}
+
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| {
+        CUBE.iter()
+            .enumerate()
+            .min_by_key(|(_, l)| (**l as i32 - v as i32).abs())
+            .map_or(0, |(i, _)| i as u8)
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = 16 + 36 * ri + 6 * gi + bi;
+
+    // The grey ramp is finer than the cube's diagonal, use it if it's closer
+    let avg = (r as u32 + g as u32 + b as u32) / 3;
+    let grey = 232 + (avg.saturating_sub(3) / 10).min(23) as u8;
+
+    if distance(rgb_256(grey), (r, g, b)) < distance(rgb_256(cube), (r, g, b)) {
+        grey
+    } else {
+        cube
+    }
+}
+
+fn rgb_256(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI[n as usize].2,
+        16..=231 => {
+            let n = n - 16;
+            (
+                CUBE[(n / 36) as usize],
+                CUBE[(n / 6 % 6) as usize],
+                CUBE[(n % 6) as usize],
+            )
+        }
+        _ => {
+            let v = 8 + 10 * (n - 232);
+            (v, v, v)
+        }
+    }
+}
+
+/// Colors for the parts of the screen and for syntax scopes, loaded from a TOML file:
+///
+/// ```toml
+/// [ui]
+/// selection = { fg = "black", bg = "white" }
+/// gutter = "darkgrey"                       # Short for { fg = "darkgrey" }
+///
+/// [scopes]
+/// comment = { fg = "#5c6370", italic = true }
+/// "markup.heading" = { fg = 75, bold = true }
+/// ```
+///
+/// Colors are names of the 16 standard colors, `#rrggbb` or a 256 color palette index, and
+/// are brought down to what the terminal supports when the theme is loaded
+#[derive(Default)]
+pub(crate) struct Theme {
+    ui: HashMap<String, Style>,
+    scopes: HashMap<String, Style>,
+}
+
+impl Theme {
+    /// Load a theme from the themes directory in the config dir, falling back to the
+    /// built-in themes
+    pub fn load(name: &str, mode: ColorMode, dir: Option<&Path>) -> Result<Theme, String> {
+        let path = dir.map(|d| d.join("themes").join(format!("{}.toml", name)));
+
+        match path.and_then(|p| fs::read_to_string(p).ok()) {
+            Some(source) => Theme::parse(&source, mode).map_err(|e| format!("{}: {}", name, e)),
+            None => match BUILTIN.iter().find(|(n, _)| *n == name) {
+                Some((_, source)) => Theme::parse(source, mode),
+                None => Err(format!("Unknown theme: {}", name)),
+            },
+        }
+    }
+
+    pub fn parse(source: &str, mode: ColorMode) -> Result<Theme, String> {
+        let tab = source
+            .parse::<Table>()
+            .map_err(|e| e.message().to_string())?;
+
+        let section = |name: &str| -> Result<HashMap<String, Style>, String> {
+            match tab.get(name) {
+                Some(Value::Table(styles)) => styles
+                    .iter()
+                    .map(|(k, v)| {
+                        Ok((
+                            k.clone(),
+                            parse_style(v, mode).map_err(|e| format!("{}: {}", k, e))?,
+                        ))
+                    })
+                    .collect(),
+                Some(_) => Err(format!("[{}] should be a table", name)),
+                None => Ok(HashMap::new()),
+            }
+        };
+
+        Ok(Theme {
+            ui: section("ui")?,
+            scopes: section("scopes")?,
+        })
+    }
+
+    /// Style of a part of the screen, unstyled if the theme doesn't mention it
+    pub fn ui(&self, element: &str) -> Style {
+        self.ui.get(element).copied().unwrap_or_default()
+    }
+
+    /// Style of a syntax scope, from the most specific entry that matches it. `string.quoted`
+    /// falls back to `string` if the theme has no `string.quoted`
+    pub fn scope(&self, scope: &str) -> Style {
+        let mut scope = scope;
+        loop {
+            if let Some(style) = self.scopes.get(scope) {
+                return *style;
+            }
+            match scope.rfind('.') {
+                Some(i) => scope = &scope[..i],
+                None => return Style::default(),
+            }
+        }
+    }
+}
+
+fn parse_style(value: &Value, mode: ColorMode) -> Result<Style, String> {
+    let color = |key: &str| -> Result<Option<Color>, String> {
+        value
+            .get(key)
+            .map(|c| parse_color(c).map(|c| mode.fit(c)))
+            .transpose()
+    };
+    let flag = |key: &str| matches!(value.get(key), Some(Value::Boolean(true)));
+
+    match value {
+        Value::Table(_) => Ok(Style {
+            fg: color("fg")?,
+            bg: color("bg")?,
+            bold: flag("bold"),
+            italic: flag("italic"),
+            underline: flag("underline"),
+        }),
+        _ => Ok(Style {
+            fg: Some(mode.fit(parse_color(value)?)),
+            ..Style::default()
+        }),
+    }
+}
+
+fn parse_color(value: &Value) -> Result<Color, String> {
+    match value {
+        Value::Integer(n) => u8::try_from(*n)
+            .map(Color::AnsiValue)
+            .map_err(|_| format!("Color index out of range: {}", n)),
+        Value::String(s) => {
+            if let Some(hex) = s.strip_prefix('#') {
+                let n = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 6)
+                    .ok_or_else(|| format!("Invalid color: {}", s))?;
+                return Ok(Color::Rgb {
+                    r: (n >> 16) as u8,
+                    g: (n >> 8) as u8,
+                    b: n as u8,
+                });
+            }
+
+            let name = s.to_ascii_lowercase().replace(['_', '-', ' '], "");
+            let name = name.replace("gray", "grey");
+            ANSI.iter()
+                .find(|(n, _, _)| *n == name)
+                .map(|(_, color, _)| *color)
+                .ok_or_else(|| format!("Unknown color: {}", s))
+        }
+        _ => Err("Colors should be a name, #rrggbb or a palette index".to_string()),
+    }
+}
@@ -0,0 +1,265 @@
+use crate::editor::Position;
+
+/// Which way a split divides its space
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Split {
+    Horizontal, // One window above the other, as with :split
+    Vertical,   // Side by side, as with :vsplit
+}
+
+/// A region of the screen, in cells
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+/// A line drawn between two windows
+#[derive(Clone, Copy)]
+pub(crate) struct Separator {
+    pub x: usize,
+    pub y: usize,
+    pub len: usize,
+    pub split: Split, // Horizontal splits are separated by a row, vertical ones by a column
+}
+
+/// A view onto a buffer. The focused window's state lives in the editor while it has focus
+pub(crate) struct Window {
+    pub id: usize,
+    pub buffer: usize, // Index of the buffer shown
+    pub cursor: Position,
+    pub term_x: usize,
+    pub term_y: usize,
+}
+
+/// A tab page, with its own windows. The current tab's windows live in the editor while it is
+/// current
+pub(crate) struct Tab {
+    pub windows: Vec<Window>,
+    pub window: usize, // Index in `windows` of the focused window
+    pub layout: Layout,
+}
+
+impl Tab {
+    /// Stands in for the current tab, whose windows are held by the editor
+    pub fn empty() -> Self {
+        Tab {
+            windows: Vec::new(),
+            window: 0,
+            layout: Layout::Window(0),
+        }
+    }
+}
+
+/// How the screen is divided between windows, as a tree of splits
+pub(crate) enum Layout {
+    Window(usize), // Id of the window
+    Split {
+        split: Split,
+        size: usize, // Rows or columns taken by `first`, not counting the separator
+        first: Box<Layout>,
+        second: Box<Layout>,
+    },
+}
+
+impl Layout {
+    /// Place the windows in `area`, in order from top left, along with the separators
+    /// between them
+    pub fn arrange(
+        &self,
+        area: Rect,
+        windows: &mut Vec<(usize, Rect)>,
+        separators: &mut Vec<Separator>,
+    ) {
+        match self {
+            Layout::Window(id) => windows.push((*id, area)),
+            Layout::Split {
+                split,
+                size,
+                first,
+                second,
+            } => {
+                let (a, sep, b) = divide(area, *split, *size);
+                first.arrange(a, windows, separators);
+                separators.push(sep);
+                second.arrange(b, windows, separators);
+            }
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        match self {
+            Layout::Window(w) => *w == id,
+            Layout::Split { first, second, .. } => first.contains(id) || second.contains(id),
+        }
+    }
+
+    /// Split window `id`, putting `new` above or left of it with `size` rows or columns
+    pub fn split(&mut self, id: usize, new: usize, split: Split, size: usize) -> bool {
+        match self {
+            Layout::Window(w) if *w == id => {
+                *self = Layout::Split {
+                    split,
+                    size,
+                    first: Box::new(Layout::Window(new)),
+                    second: Box::new(Layout::Window(id)),
+                };
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split { first, second, .. } => {
+                first.split(id, new, split, size) || second.split(id, new, split, size)
+            }
+        }
+    }
+
+    /// Remove window `id`, giving its space to whatever it was split from
+    pub fn remove(&mut self, id: usize) -> bool {
+        let Layout::Split { first, second, .. } = self else {
+            return false;
+        };
+
+        let rest = match (&**first, &**second) {
+            (Layout::Window(w), _) if *w == id => {
+                std::mem::replace(&mut **second, Layout::Window(0))
+            }
+            (_, Layout::Window(w)) if *w == id => {
+                std::mem::replace(&mut **first, Layout::Window(0))
+            }
+            _ => return first.remove(id) || second.remove(id),
+        };
+
+        *self = rest;
+        true
+    }
+
+    /// Grow window `id` by `delta` rows or columns, from the innermost split of the given
+    /// kind around it. Returns false if there is no such split
+    pub fn resize(&mut self, area: Rect, id: usize, split: Split, delta: isize) -> bool {
+        let Layout::Split {
+            split: s,
+            size,
+            first,
+            second,
+        } = self
+        else {
+            return false;
+        };
+
+        let (a, _, b) = divide(area, *s, *size);
+        if first.resize(a, id, split, delta) || second.resize(b, id, split, delta) {
+            return true;
+        }
+        if *s != split {
+            return false;
+        }
+
+        let delta = if first.contains(id) {
+            delta
+        } else if second.contains(id) {
+            -delta
+        } else {
+            return false;
+        };
+
+        let current = divide(area, *s, *size).0;
+        let current = match s {
+            Split::Horizontal => current.height,
+            Split::Vertical => current.width,
+        };
+        *size = current.saturating_add_signed(delta).max(1);
+        true
+    }
+
+    /// Share out the space evenly between windows
+    pub fn equalize(&mut self, area: Rect) {
+        if let Layout::Split {
+            split,
+            size,
+            first,
+            second,
+        } = self
+        {
+            let (a, b) = (first.count(*split), second.count(*split));
+            let total = match split {
+                Split::Horizontal => area.height,
+                Split::Vertical => area.width,
+            };
+            *size = total.saturating_sub(1) * a / (a + b);
+
+            let (area_a, _, area_b) = divide(area, *split, *size);
+            first.equalize(area_a);
+            second.equalize(area_b);
+        }
+    }
+
+    /// Number of windows side by side in the direction of `split`
+    fn count(&self, split: Split) -> usize {
+        match self {
+            Layout::Window(_) => 1,
+            Layout::Split {
+                split: s,
+                first,
+                second,
+                ..
+            } if *s == split => first.count(split) + second.count(split),
+            Layout::Split { first, second, .. } => first.count(split).max(second.count(split)),
+        }
+    }
+}
+
+/// Divide `area` into the space for the first half of a split, the separator and the rest.
+/// Each half keeps at least one row or column when there's room
+fn divide(area: Rect, split: Split, size: usize) -> (Rect, Separator, Rect) {
+    let total = match split {
+        Split::Horizontal => area.height,
+        Split::Vertical => area.width,
+    };
+    let size = size.min(total.saturating_sub(2)).max(1).min(total);
+    let rest = total.saturating_sub(size + 1);
+
+    match split {
+        Split::Horizontal => (
+            Rect {
+                height: size,
+                ..area
+            },
+            Separator {
+                x: area.x,
+                y: area.y + size,
+                len: area.width,
+                split,
+            },
+            Rect {
+                y: area.y + size + 1,
+                height: rest,
+                ..area
+            },
+        ),
+        Split::Vertical => (
+            Rect {
+                width: size,
+                ..area
+            },
+            Separator {
+                x: area.x + size,
+                y: area.y,
+                len: area.height,
+                split,
+            },
+            Rect {
+                x: area.x + size + 1,
+                width: rest,
+                ..area
+            },
+        ),
+    }
+}
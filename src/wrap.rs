@@ -0,0 +1,94 @@
+use std::ops::Range;
+
+use crate::term::next_col;
+
+/// Settings for splitting long lines across screen rows
+#[derive(Clone)]
+pub(crate) struct Wrap {
+    pub width: usize,      // Columns available for text
+    pub linebreak: bool,   // Break after whitespace rather than at the last column
+    pub showbreak: String, // Drawn at the start of continuation rows
+    pub tabstop: usize,
+}
+
+/// One screen row of a logical line
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Row {
+    pub bytes: Range<usize>,
+    pub start_col: usize,   // Display column of the first character
+    pub continuation: bool, // Not the first row of the line
+}
+
+impl Wrap {
+    fn row_width(&self, continuation: bool) -> usize {
+        if continuation {
+            self.width
+                .saturating_sub(self.showbreak.chars().count())
+                .max(1)
+        } else {
+            self.width.max(1)
+        }
+    }
+
+    /// Split a line into the screen rows it takes up. Always returns at least one row
+    pub fn rows(&self, line: &str) -> Vec<Row> {
+        let mut rows = Vec::new();
+        let mut start = 0;
+        let mut start_col = 0;
+        let mut col = 0;
+        // Where to break for `linebreak`, the byte and column after the last whitespace
+        let mut last_space = None;
+
+        for (i, ch) in line.char_indices() {
+            let next = next_col(col, ch, self.tabstop);
+
+            if next - start_col > self.row_width(!rows.is_empty()) && i > start {
+                let (brk, brk_col) = match last_space {
+                    Some(b) if self.linebreak => b,
+                    _ => (i, col),
+                };
+
+                rows.push(Row {
+                    bytes: start..brk,
+                    start_col,
+                    continuation: !rows.is_empty(),
+                });
+                start = brk;
+                start_col = brk_col;
+                last_space = None;
+            }
+
+            if ch.is_whitespace() {
+                last_space = Some((i + ch.len_utf8(), next));
+            }
+
+            col = next;
+        }
+
+        rows.push(Row {
+            bytes: start..line.len(),
+            start_col,
+            continuation: !rows.is_empty(),
+        });
+
+        rows
+    }
+
+    /// Index of the row holding the given byte index
+    pub fn row_of(&self, rows: &[Row], index: usize) -> usize {
+        rows.iter()
+            .rposition(|r| r.bytes.start <= index)
+            .unwrap_or(0)
+    }
+
+    /// Screen column of a display column within a row, including the `showbreak` prefix
+    pub fn screen_col(&self, row: &Row, col: usize) -> usize {
+        let prefix = if row.continuation {
+            self.showbreak.chars().count()
+        } else {
+            0
+        };
+
+        (prefix + col.saturating_sub(row.start_col)).min(self.width.saturating_sub(1))
+    }
+}